@@ -0,0 +1,569 @@
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use hyper::{Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+use tokio::time::{sleep, Instant, Sleep};
+use tower::{Layer, Service};
+
+use crate::{
+    circuit::authority,
+    classify::{Classification, Classify, DefaultClassifier},
+};
+
+/// Limit the rate of requests to an authority (host and port) once it starts throttling them
+///
+/// Each authority gets a token bucket, which every request waits on, including the first attempt
+/// of each request when this layer is below the [`RetryLayer`](crate::retry::RetryLayer). The
+/// rate is unlimited until the authority throttles a request, by responding with
+/// `429 Too Many Requests` or `503 Service Unavailable`, by refusing an HTTP/2 stream, or when the
+/// classifier `C` asks to retry after a delay. It then starts at `initial_rate` requests per
+/// second, is multiplied by `decrease` on each throttled request, and grows back on successful
+/// responses, see [`Growth`]. Successful responses are those that `C` doesn't retry.
+///
+/// All the services created by a layer, and their clones, share the same buckets.
+#[derive(Clone)]
+pub struct AdaptiveLayer<C = DefaultClassifier> {
+    config: Config,
+    classifier: C,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    /// Rate after the first throttled response, in requests per second
+    initial_rate: f64,
+    /// The rate is never cut below this
+    min_rate: f64,
+    /// Factor applied to the rate on throttled responses
+    decrease: f64,
+    growth: Growth,
+}
+
+/// How the rate grows back after a throttled response
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum Growth {
+    /// Add this many requests per second on each response that wasn't throttled
+    Additive(f64),
+    /// Follow a cubic curve back to the rate before the last cut, then beyond it, as with the
+    /// CUBIC congestion control algorithm
+    Cubic,
+}
+
+/// Scaling constant of [`Growth::Cubic`]
+const CUBIC_SCALE: f64 = 0.4;
+
+/// Bounds of the rates, in requests per second
+const MIN_RATE: f64 = 0.01;
+const MAX_RATE: f64 = 1_000_000.0;
+
+impl AdaptiveLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C> AdaptiveLayer<C> {
+    /// # Panics
+    ///
+    /// If `rate` is not a positive number.
+    /// Rate after the first throttled request, clamped between 0.01 and 1,000,000
+    #[allow(dead_code)]
+    pub fn with_initial_rate(mut self, rate: f64) -> Self {
+        self.config.initial_rate = clamp_rate(rate);
+        self
+    }
+
+    /// The rate is never cut below `rate`, clamped between 0.01 and 1,000,000
+    #[allow(dead_code)]
+    pub fn with_min_rate(mut self, rate: f64) -> Self {
+        self.config.min_rate = clamp_rate(rate);
+        self
+    }
+
+    /// Multiply the rate by `decrease` on throttled responses, clamped between 0.1 and 1.0
+    #[allow(dead_code)]
+    pub fn with_decrease(mut self, decrease: f64) -> Self {
+        self.config.decrease = decrease.clamp(0.1, 1.0);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_growth(mut self, growth: Growth) -> Self {
+        self.config.growth = growth;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_classifier<D>(self, classifier: D) -> AdaptiveLayer<D> {
+        AdaptiveLayer {
+            config: self.config,
+            classifier,
+            buckets: self.buckets,
+        }
+    }
+}
+
+/// NaN is clamped to the minimum
+fn clamp_rate(rate: f64) -> f64 {
+    if rate.is_nan() {
+        MIN_RATE
+    } else {
+        rate.clamp(MIN_RATE, MAX_RATE)
+    }
+}
+
+impl Default for AdaptiveLayer {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                initial_rate: 10.0,
+                min_rate: 0.5,
+                decrease: 0.7,
+                growth: Growth::Additive(1.0),
+            },
+            classifier: DefaultClassifier::default(),
+            buckets: Default::default(),
+        }
+    }
+}
+
+impl<S, C> Layer<S> for AdaptiveLayer<C>
+where
+    C: Clone,
+{
+    type Service = Adaptive<S, C>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Adaptive {
+            config: self.config,
+            classifier: self.classifier.clone(),
+            buckets: self.buckets.clone(),
+            service,
+        }
+    }
+}
+
+/// Service created by [`AdaptiveLayer`]
+#[derive(Clone)]
+pub struct Adaptive<S, C = DefaultClassifier> {
+    config: Config,
+    classifier: C,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    service: S,
+}
+
+impl<S, C, T, B> Service<Request<T>> for Adaptive<S, C>
+where
+    S: Service<Request<T>, Response = Response<B>> + Clone,
+    S::Error: StdError + 'static,
+    C: Classify<Request<()>, Response<B>, S::Error> + Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S, C, Request<T>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        let authority = authority(req.uri());
+        let wait = {
+            let mut buckets = self.buckets.lock().expect("adaptive lock poisoned");
+            buckets.entry(authority.clone()).or_default().acquire()
+        };
+        // Keep what the classifier may need from the request
+        let mut head = Request::new(());
+        *head.method_mut() = req.method().clone();
+        *head.uri_mut() = req.uri().clone();
+        *head.version_mut() = req.version();
+        *head.headers_mut() = req.headers().clone();
+
+        let feedback = Feedback {
+            config: self.config,
+            classifier: self.classifier.clone(),
+            head,
+            buckets: self.buckets.clone(),
+            authority,
+        };
+
+        if wait.is_zero() {
+            return ResponseFuture::Called {
+                future: self.service.call(req),
+                feedback,
+            };
+        }
+        // The service is ready now, so keep it for when the wait is over
+        let clone = self.service.clone();
+        let service = mem::replace(&mut self.service, clone);
+        ResponseFuture::Waiting {
+            sleep: sleep(wait),
+            service,
+            req: Some(req),
+            feedback: Some(feedback),
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`Adaptive`]
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<S, C, Req>
+    where
+        S: Service<Req>,
+    {
+        Waiting {
+            #[pin]
+            sleep: Sleep,
+            service: S,
+            req: Option<Req>,
+            feedback: Option<Feedback<C>>,
+        },
+        Called {
+            #[pin]
+            future: S::Future,
+            feedback: Feedback<C>,
+        },
+    }
+}
+
+impl<S, C, T, B> Future for ResponseFuture<S, C, Request<T>>
+where
+    S: Service<Request<T>, Response = Response<B>>,
+    S::Error: StdError + 'static,
+    C: Classify<Request<()>, Response<B>, S::Error>,
+{
+    type Output = Result<Response<B>, S::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                ResponseFutureProj::Waiting {
+                    sleep,
+                    service,
+                    req,
+                    feedback,
+                } => {
+                    ready!(sleep.poll(cx));
+                    let future = service.call(req.take().expect("polled after completion"));
+                    let feedback = feedback.take().expect("polled after completion");
+                    self.set(ResponseFuture::Called { future, feedback });
+                }
+                ResponseFutureProj::Called { future, feedback } => {
+                    let result = ready!(future.poll(cx));
+                    feedback.record(result.as_ref());
+                    return Poll::Ready(result);
+                }
+            }
+        }
+    }
+}
+
+/// Where to record how the authority responded
+pub struct Feedback<C> {
+    config: Config,
+    classifier: C,
+    head: Request<()>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    authority: String,
+}
+
+impl<C> Feedback<C> {
+    fn record<B, E>(&self, result: Result<&Response<B>, &E>)
+    where
+        E: StdError + 'static,
+        C: Classify<Request<()>, Response<B>, E>,
+    {
+        let throttled = match result {
+            Ok(response) => {
+                let status = response.status();
+                status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+            }
+            Err(err) => is_throttling(err),
+        };
+        let classification = self.classifier.classify(&self.head, result);
+
+        let mut buckets = self.buckets.lock().expect("adaptive lock poisoned");
+        let bucket = match buckets.get_mut(&self.authority) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        match classification {
+            _ if throttled => bucket.throttle(&self.config),
+            Classification::RetryAfter(_) => bucket.throttle(&self.config),
+            Classification::DontRetry if result.is_ok() => bucket.grow(&self.config),
+            // Other failures say nothing about the rate
+            _ => (),
+        }
+    }
+}
+
+/// Whether the server refused the request because of its load
+///
+/// HTTP/2 servers refuse streams, or ask the client to calm down.
+fn is_throttling(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<h2::Error>() {
+            return matches!(
+                err.reason(),
+                Some(h2::Reason::REFUSED_STREAM | h2::Reason::ENHANCE_YOUR_CALM)
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// Requests per second, unlimited until the first throttled response
+    rate: Option<f64>,
+    /// Available tokens, negative when requests are already waiting for the next ones
+    tokens: f64,
+    /// When `tokens` was last refilled
+    refilled: Option<Instant>,
+    /// Rate before the last cut, and when it happened, for [`Growth::Cubic`]
+    last_cut: Option<(f64, Instant)>,
+}
+
+impl Bucket {
+    /// Take a token, returning how long to wait for it
+    fn acquire(&mut self) -> Duration {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+        self.refill(rate);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = self
+            .refilled
+            .map(|refilled| now - refilled)
+            .unwrap_or_default();
+        // Allow bursts of up to one second of requests
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate.max(1.0));
+        self.refilled = Some(now);
+    }
+
+    fn throttle(&mut self, config: &Config) {
+        let rate = match self.rate {
+            Some(rate) => {
+                self.refill(rate);
+                rate
+            }
+            None => {
+                self.tokens = 0.0;
+                self.refilled = Some(Instant::now());
+                config.initial_rate / config.decrease
+            }
+        };
+        self.last_cut = Some((rate, Instant::now()));
+        self.rate = Some((rate * config.decrease).max(config.min_rate));
+    }
+
+    fn grow(&mut self, config: &Config) {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        self.refill(rate);
+        let rate = match (config.growth, self.last_cut) {
+            (Growth::Additive(increase), _) => rate + increase,
+            (Growth::Cubic, Some((max_rate, cut))) => {
+                let k = (max_rate * (1.0 - config.decrease) / CUBIC_SCALE).cbrt();
+                let t = cut.elapsed().as_secs_f64();
+                (CUBIC_SCALE * (t - k).powi(3) + max_rate).max(rate)
+            }
+            (Growth::Cubic, None) => rate,
+        };
+        // A negative increase would otherwise bring the rate down to zero
+        self.rate = Some(clamp_rate(rate).max(config.min_rate));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::mock::Mock;
+
+    fn config() -> Config {
+        AdaptiveLayer::new().config
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+
+    fn assert_wait(wait: Duration, millis: u64) {
+        assert_close(wait.as_secs_f64(), millis as f64 / 1000.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_until_throttled() {
+        let config = config();
+        let mut bucket = Bucket::default();
+        bucket.grow(&config);
+        for _ in 0..100 {
+            assert_eq!(bucket.acquire(), Duration::ZERO);
+        }
+        assert!(bucket.rate.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_are_spaced_by_rate() {
+        let config = config();
+        let mut bucket = Bucket::default();
+        bucket.throttle(&config);
+        assert_close(bucket.rate.unwrap(), 10.0);
+
+        assert_wait(bucket.acquire(), 100);
+        assert_wait(bucket.acquire(), 200);
+
+        // Refills up to one second of requests
+        tokio::time::advance(Duration::from_secs(5)).await;
+        for _ in 0..10 {
+            assert_eq!(bucket.acquire(), Duration::ZERO);
+        }
+        assert_wait(bucket.acquire(), 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_cuts_down_to_min_rate() {
+        let config = AdaptiveLayer::new()
+            .with_decrease(0.5)
+            .with_min_rate(3.0)
+            .config;
+        let mut bucket = Bucket::default();
+        bucket.throttle(&config);
+        assert_close(bucket.rate.unwrap(), 10.0);
+        bucket.throttle(&config);
+        assert_close(bucket.rate.unwrap(), 5.0);
+        bucket.throttle(&config);
+        assert_close(bucket.rate.unwrap(), 3.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn additive_growth() {
+        let config = config();
+        let mut bucket = Bucket::default();
+        bucket.throttle(&config);
+        bucket.grow(&config);
+        bucket.grow(&config);
+        assert_close(bucket.rate.unwrap(), 12.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn growth_never_goes_below_min_rate() {
+        let config = AdaptiveLayer::new()
+            .with_growth(Growth::Additive(-100.0))
+            .config;
+        let mut bucket = Bucket::default();
+        bucket.throttle(&config);
+        bucket.grow(&config);
+        assert_close(bucket.rate.unwrap(), config.min_rate);
+        assert!(bucket.acquire() < Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cubic_growth() {
+        let config = AdaptiveLayer::new().with_growth(Growth::Cubic).config;
+        let mut bucket = Bucket::default();
+        bucket.throttle(&config);
+        bucket.throttle(&config);
+        assert_close(bucket.rate.unwrap(), 7.0);
+
+        // Back to the rate before the cut after `k` seconds, then beyond it
+        let k = (10.0 * 0.3 / CUBIC_SCALE).cbrt();
+        bucket.grow(&config);
+        assert_close(bucket.rate.unwrap(), 7.0);
+        tokio::time::advance(Duration::from_secs_f64(k)).await;
+        bucket.grow(&config);
+        assert_close(bucket.rate.unwrap(), 10.0);
+        tokio::time::advance(Duration::from_secs_f64(k)).await;
+        bucket.grow(&config);
+        assert_close(bucket.rate.unwrap(), 13.0);
+    }
+
+    #[test]
+    fn rates_are_clamped() {
+        let config = AdaptiveLayer::new()
+            .with_initial_rate(-1.0)
+            .with_min_rate(f64::NAN)
+            .config;
+        assert_eq!(config.initial_rate, MIN_RATE);
+        assert_eq!(config.min_rate, MIN_RATE);
+        let config = AdaptiveLayer::new().with_initial_rate(f64::INFINITY).config;
+        assert_eq!(config.initial_rate, MAX_RATE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn feedback_from_results() {
+        let layer = AdaptiveLayer::new();
+        let mock = Mock::new()
+            .status(429)
+            .status(500)
+            .status(200)
+            .error(h2::Error::from(h2::Reason::REFUSED_STREAM))
+            .error(h2::Error::from(h2::Reason::INTERNAL_ERROR));
+        let rate = || -> f64 {
+            let buckets = layer.buckets.lock().unwrap();
+            buckets["example.com:80"].rate.unwrap()
+        };
+        let send = || {
+            let req = Request::get("http://example.com/").body(()).unwrap();
+            layer.layer(mock.clone()).oneshot(req)
+        };
+
+        assert_eq!(send().await.unwrap().status(), 429);
+        assert_close(rate(), 10.0);
+        // Retryable failures don't grow the rate
+        assert_eq!(send().await.unwrap().status(), 500);
+        assert_close(rate(), 10.0);
+        assert_eq!(send().await.unwrap().status(), 200);
+        assert_close(rate(), 11.0);
+        // A refused stream is throttling, other errors are not
+        assert!(send().await.is_err());
+        assert_close(rate(), 7.7);
+        assert!(send().await.is_err());
+        assert_close(rate(), 7.7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_wait_for_tokens() {
+        let layer = AdaptiveLayer::new();
+        let mock = Mock::new().status(503).status(503);
+        let start = Instant::now();
+        for _ in 0..3 {
+            let req = Request::get("http://example.com/").body(()).unwrap();
+            let response: Response<Body> = layer.layer(mock.clone()).oneshot(req).await.unwrap();
+            drop(response);
+        }
+        let received: Vec<_> = mock.requests().iter().map(|seen| seen.at - start).collect();
+        assert_eq!(received[0], Duration::ZERO);
+        // 10 requests per second, then 7
+        assert_wait(received[1], 100);
+        assert_wait(received[2] - received[1], 143);
+    }
+}
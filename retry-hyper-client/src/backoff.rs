@@ -1,28 +1,105 @@
-use std::{future::Future, pin::Pin, time::Duration};
+use std::{
+    error::Error as StdError,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
 
-use hyper::{Body, Error, Request, Response};
-use rand::distributions::Uniform;
+use hyper::{
+    header::{HeaderName, HOST},
+    Request, Response,
+};
+use pin_project_lite::pin_project;
 #[cfg(feature = "rand")]
-use rand::Rng;
-use tokio::time::sleep;
-use tower::retry::Policy;
+use rand::{Rng, RngCore};
+use tower::retry::{budget::Budget, Policy};
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::{
+    body::Replay,
+    classify::{Classification, Classify, DefaultClassifier},
+    error_kind::ErrorKind,
+    extensions::ExtensionRegistry,
+    failover::Endpoints,
+    history::Outcome,
+    hook::{BeforeRetry, Rewrite},
+    idempotency::{is_idempotent, is_unsent, IDEMPOTENCY_KEY},
+    retry::{RetryPolicy, Template},
+    retry_after::retry_after,
+    schedule::{DelaySchedule, Exponential},
+    sleep::{Sleeper, TokioSleeper},
+};
+#[cfg(feature = "rand")]
+use crate::{idempotency::generate_key, rng::JitterRng};
 
 /// Exponential backoff with maximum delay
+///
+/// The exponential curve can be replaced by any [`DelaySchedule`] with
+/// [`with_schedule`](Self::with_schedule).
+///
+/// Whether an attempt is retried is decided by the classifier `C`, see [`Classify`], and the
+/// delays are waited with the timer `S`, see [`Sleeper`].
 #[derive(Clone)]
-pub struct Backoff {
+pub struct Backoff<C = DefaultClassifier, S = TokioSleeper> {
     /// Maximum number of attempts before failing
     attempts: usize,
+    /// Number of the next attempt, starting at 1
+    attempt: usize,
     /// Initial delay
     delay: Duration,
     /// Multiplier for each delay
     multiplier: f64,
+    /// Delays to use instead of `delay` and `multiplier`
+    schedule: Option<Arc<dyn DelaySchedule>>,
     /// Maximum delay
     max_delay: Option<Duration>,
-    /// Jitter to add on calls
-    ///
-    /// If this contains some value, this will add a random jitter between `-jitter` and `+jitter`.
+    /// Jitter to apply to delays, see [`Jitter`] for the strategies
     #[cfg(feature = "rand")]
     jitter: Option<Jitter>,
+    /// Previous delay, used by [`Jitter::Decorrelated`]
+    #[cfg(feature = "rand")]
+    prev_delay: Option<Duration>,
+    /// Generator for the jitter, `rand::thread_rng()` if unset
+    #[cfg(feature = "rand")]
+    rng: Option<JitterRng>,
+    /// Records the delays chosen before each retry
+    delay_log: Option<DelayLog>,
+    /// Maximum delay a server can ask for through `Retry-After` or rate-limit headers
+    max_retry_after: Duration,
+    /// Decides which results are retried
+    classifier: C,
+    /// Add an `Idempotency-Key` header to requests that don't have one
+    ///
+    /// Non-idempotent requests are only replayed with a generated key under this crate's
+    /// [`RetryLayer`](crate::retry::RetryLayer), as `tower::retry::RetryLayer` sends the first
+    /// attempt without going through `clone_request`, so without the key.
+    #[cfg(feature = "rand")]
+    idempotency_key: bool,
+    /// Maximum time between the start of the first attempt and the start of the last one
+    max_elapsed: Option<Duration>,
+    /// Retry budget shared with other policies
+    ///
+    /// Completed requests deposit into it, and each retry withdraws from it.
+    budget: Option<Arc<Budget>>,
+    /// Timer for the delays and the deadline
+    sleeper: S,
+    /// Header carrying the attempt number, added to each attempt
+    attempt_header: Option<AttemptHeader>,
+    /// Total delay waited before the next attempt
+    total_delay: Duration,
+    /// Request extensions copied to each attempt
+    extensions: ExtensionRegistry,
+    /// Hook rewriting the request before each retry
+    before_retry: Option<Arc<dyn BeforeRetry>>,
+    /// Head of the request returned by the last call to `before_retry`
+    head: Option<Arc<Request<()>>>,
+    /// Replicas to fail over between
+    endpoints: Option<Endpoints>,
+    /// Index of the endpoint for the next attempt
+    endpoint: usize,
 }
 
 impl Backoff {
@@ -30,7 +107,9 @@ impl Backoff {
     pub fn new() -> Self {
         Self::default()
     }
+}
 
+impl<C, S: Sleeper> Backoff<C, S> {
     #[allow(dead_code)]
     pub fn with_attempts(self, attempts: usize) -> Self {
         Self { attempts, ..self }
@@ -46,6 +125,15 @@ impl Backoff {
         Self { multiplier, ..self }
     }
 
+    /// Use `schedule` for the delays, instead of the exponential curve
+    #[allow(dead_code)]
+    pub fn with_schedule<D: DelaySchedule + 'static>(self, schedule: D) -> Self {
+        Self {
+            schedule: Some(Arc::new(schedule)),
+            ..self
+        }
+    }
+
     #[allow(dead_code)]
     pub fn with_max_delay(self, max_delay: Duration) -> Self {
         Self {
@@ -63,44 +151,487 @@ impl Backoff {
         }
     }
 
-    pub async fn next(&self) -> Self {
-        let delay = self.delay;
+    #[allow(dead_code)]
+    pub fn with_max_retry_after(self, max_retry_after: Duration) -> Self {
+        Self {
+            max_retry_after,
+            ..self
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_classifier<D>(self, classifier: D) -> Backoff<D, S> {
+        Backoff {
+            attempts: self.attempts,
+            attempt: self.attempt,
+            delay: self.delay,
+            multiplier: self.multiplier,
+            schedule: self.schedule,
+            max_delay: self.max_delay,
+            #[cfg(feature = "rand")]
+            jitter: self.jitter,
+            #[cfg(feature = "rand")]
+            prev_delay: self.prev_delay,
+            #[cfg(feature = "rand")]
+            rng: self.rng,
+            delay_log: self.delay_log,
+            max_retry_after: self.max_retry_after,
+            classifier,
+            #[cfg(feature = "rand")]
+            idempotency_key: self.idempotency_key,
+            max_elapsed: self.max_elapsed,
+            budget: self.budget,
+            sleeper: self.sleeper,
+            attempt_header: self.attempt_header,
+            total_delay: self.total_delay,
+            extensions: self.extensions,
+            before_retry: self.before_retry,
+            head: self.head,
+            endpoints: self.endpoints,
+            endpoint: self.endpoint,
+        }
+    }
+
+    #[cfg(feature = "rand")]
+    #[allow(dead_code)]
+    pub fn with_idempotency_key(self) -> Self {
+        Self {
+            idempotency_key: true,
+            ..self
+        }
+    }
+
+    /// Add a [`RETRY_ATTEMPT`] header with the attempt number to each attempt
+    ///
+    /// Any such header already on the request is replaced. This only works with this crate's
+    /// [`RetryLayer`](crate::retry::RetryLayer), like
+    /// [`with_idempotency_key`](Self::with_idempotency_key).
+    #[allow(dead_code)]
+    pub fn with_attempt_header(self) -> Self {
+        Self {
+            attempt_header: Some(self.attempt_header.unwrap_or_default()),
+            ..self
+        }
+    }
+
+    /// Use `name` instead of [`RETRY_ATTEMPT`] for the attempt header
+    #[allow(dead_code)]
+    pub fn with_attempt_header_name(self, name: HeaderName) -> Self {
+        let attempt_header = self.attempt_header.clone().unwrap_or_default();
+        Self {
+            attempt_header: Some(AttemptHeader {
+                name,
+                ..attempt_header
+            }),
+            ..self
+        }
+    }
+
+    /// Add the total delay waited so far to the attempt header, as in `3; delay=1500ms`
+    #[allow(dead_code)]
+    pub fn with_attempt_header_delay(self) -> Self {
+        let attempt_header = self.attempt_header.clone().unwrap_or_default();
+        Self {
+            attempt_header: Some(AttemptHeader {
+                delay: true,
+                ..attempt_header
+            }),
+            ..self
+        }
+    }
+
+    /// Copy request extensions of type `X` to each attempt
+    ///
+    /// Other extensions are dropped, except for those registered by default, see
+    /// [`ExtensionRegistry`].
+    #[allow(dead_code)]
+    pub fn with_extension<X>(self) -> Self
+    where
+        X: Clone + Send + Sync + 'static,
+    {
+        Self {
+            extensions: self.extensions.with::<X>(),
+            ..self
+        }
+    }
+
+    /// Copy the request extensions registered in `extensions` to each attempt
+    #[allow(dead_code)]
+    pub fn with_extensions(self, extensions: ExtensionRegistry) -> Self {
+        Self { extensions, ..self }
+    }
+
+    /// Call `before_retry` once the delay before each retry is over, to rewrite the request
+    ///
+    /// See [`BeforeRetry`] for what it can change. `tower::retry::RetryLayer` builds each attempt
+    /// before the previous one is sent, so with it the rewritten request is only sent from the
+    /// attempt after the next one. This crate's [`RetryLayer`](crate::retry::RetryLayer) sends it
+    /// on the next attempt.
+    #[allow(dead_code)]
+    pub fn with_before_retry<H: BeforeRetry + 'static>(self, before_retry: H) -> Self {
+        Self {
+            before_retry: Some(Arc::new(before_retry)),
+            ..self
+        }
+    }
+
+    /// Send the first attempt to the primary endpoint, and fail over to the others on retries
+    ///
+    /// The authority of the request URI is replaced, so the URI must be absolute. Errors and
+    /// retryable responses mark their endpoint as unhealthy. Attempts rejected by the
+    /// [`CircuitBreakerLayer`](crate::circuit::CircuitBreakerLayer) are retried on another
+    /// endpoint.
+    ///
+    /// This only works with this crate's [`RetryLayer`](crate::retry::RetryLayer), like
+    /// [`with_attempt_header`](Self::with_attempt_header). `tower::retry::RetryLayer` builds each
+    /// attempt before the previous one is sent, so retries go to the endpoint one step behind, and
+    /// the first retry goes to the primary endpoint again.
+    #[allow(dead_code)]
+    pub fn with_endpoints(self, endpoints: Endpoints) -> Self {
+        Self {
+            endpoints: Some(endpoints),
+            ..self
+        }
+    }
+
+    /// Draw the jitter from `rng` instead of `rand::thread_rng()`
+    ///
+    /// Each clone of the policy forks its own generator from `rng`, see [`JitterRng`].
+    #[cfg(feature = "rand")]
+    #[allow(dead_code)]
+    pub fn with_rng<R: RngCore + Send + 'static>(self, rng: R) -> Self {
+        Self {
+            rng: Some(JitterRng::new(rng)),
+            ..self
+        }
+    }
+
+    /// Draw the jitter from a generator seeded with `seed`, to make the delays reproducible
+    #[cfg(feature = "rand")]
+    #[allow(dead_code)]
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            rng: Some(JitterRng::from_seed(seed)),
+            ..self
+        }
+    }
+
+    /// Record the delays chosen before each retry into `log`
+    #[allow(dead_code)]
+    pub fn with_delay_log(self, log: DelayLog) -> Self {
+        Self {
+            delay_log: Some(log),
+            ..self
+        }
+    }
+
+    /// Wait and measure time with `sleeper` instead of Tokio's timer
+    #[allow(dead_code)]
+    pub fn with_sleeper<T: Sleeper>(self, sleeper: T) -> Backoff<C, T> {
+        Backoff {
+            attempts: self.attempts,
+            attempt: self.attempt,
+            delay: self.delay,
+            multiplier: self.multiplier,
+            schedule: self.schedule,
+            max_delay: self.max_delay,
+            #[cfg(feature = "rand")]
+            jitter: self.jitter,
+            #[cfg(feature = "rand")]
+            prev_delay: self.prev_delay,
+            #[cfg(feature = "rand")]
+            rng: self.rng,
+            delay_log: self.delay_log,
+            max_retry_after: self.max_retry_after,
+            classifier: self.classifier,
+            #[cfg(feature = "rand")]
+            idempotency_key: self.idempotency_key,
+            max_elapsed: self.max_elapsed,
+            budget: self.budget,
+            sleeper,
+            attempt_header: self.attempt_header,
+            total_delay: self.total_delay,
+            extensions: self.extensions,
+            before_retry: self.before_retry,
+            head: self.head,
+            endpoints: self.endpoints,
+            endpoint: self.endpoint,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_max_elapsed(self, max_elapsed: Duration) -> Self {
+        Self {
+            max_elapsed: Some(max_elapsed),
+            ..self
+        }
+    }
+
+    /// Share `budget` between all the requests using this policy
+    ///
+    /// See `tower::retry::budget::Budget` for how it limits the ratio of retries to requests.
+    #[allow(dead_code)]
+    pub fn with_budget(self, budget: Arc<Budget>) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Delay from the schedule for the given retry, capped to `max_delay`
+    fn scheduled_delay(&self, retry: usize) -> Duration {
+        let delay = match &self.schedule {
+            Some(schedule) => schedule.delay(retry),
+            None => Exponential {
+                initial: self.delay,
+                multiplier: self.multiplier,
+            }
+            .delay(retry),
+        };
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        }
+    }
+
+    /// Delay before the next attempt, at least `min_delay` if set
+    fn delay(&self, min_delay: Option<Duration>) -> Duration {
+        let scheduled = self.scheduled_delay(self.attempt - 1);
         #[cfg(feature = "rand")]
         let delay = match self.jitter {
-            Some(Jitter::Duration(jitter)) => {
-                let mut rng = rand::thread_rng();
-                let jitter = rng.sample(Uniform::new(Duration::new(0, 0), jitter));
-                self.delay + jitter
+            Some(jitter) => match &self.rng {
+                Some(rng) => self.jitter(jitter, &mut *rng.lock()),
+                None => self.jitter(jitter, &mut rand::thread_rng()),
+            },
+            None => scheduled,
+        };
+        #[cfg(not(feature = "rand"))]
+        let delay = scheduled;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            scheduled_ms = scheduled.as_millis() as u64,
+            jitter_ms = delay.as_millis() as i64 - scheduled.as_millis() as i64,
+            min_delay_ms = min_delay.map(|min_delay| min_delay.as_millis() as u64),
+            "chose delay"
+        );
+        match min_delay {
+            Some(min_delay) if min_delay > delay => min_delay,
+            _ => delay,
+        }
+    }
+
+    /// Apply `jitter` to the delay for the next attempt
+    #[cfg(feature = "rand")]
+    fn jitter<R: Rng>(&self, jitter: Jitter, rng: &mut R) -> Duration {
+        let delay = self.scheduled_delay(self.attempt - 1);
+        match jitter {
+            // Schedules saturate to `Duration::MAX`, so the jitter has to saturate too
+            Jitter::Duration(jitter) => {
+                delay.saturating_add(rng.gen_range(Duration::ZERO..=jitter))
             }
-            Some(Jitter::Percentage(percentage)) => {
-                let mut rng = rand::thread_rng();
-                let jitter = rng.sample(Uniform::new(0.0, percentage));
-                self.delay.mul_f64(1.0 + jitter)
+            Jitter::Percentage(percentage) => {
+                scale(delay, 1.0 + rng.gen_range(0.0..=percentage.max(0.0)))
             }
-            None => delay,
+            Jitter::SymmetricDuration(jitter) => {
+                rng.gen_range(delay.saturating_sub(jitter)..=delay.saturating_add(jitter))
+            }
+            Jitter::SymmetricPercentage(percentage) => {
+                let percentage = percentage.clamp(0.0, 1.0);
+                scale(delay, 1.0 + rng.gen_range(-percentage..=percentage))
+            }
+            Jitter::Full => rng.gen_range(Duration::ZERO..=delay),
+            Jitter::Equal => delay / 2 + rng.gen_range(Duration::ZERO..=delay / 2),
+            Jitter::Decorrelated => {
+                let base = self.scheduled_delay(0);
+                let upper = self.prev_delay.unwrap_or(base).max(base).saturating_mul(3);
+                let delay = rng.gen_range(base..=upper);
+                match self.max_delay {
+                    Some(max_delay) => delay.min(max_delay),
+                    None => delay,
+                }
+            }
+        }
+    }
+
+    /// Wait for `delay`, then move on to the next attempt
+    pub fn next(&self, delay: Duration) -> BackoffFuture<C, S>
+    where
+        C: Clone,
+        S: Clone,
+    {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            delay_ms = delay.as_millis() as u64,
+            "waiting before next attempt"
+        );
+        if let Some(log) = &self.delay_log {
+            log.record(delay);
+        }
+        let sleep = self.sleeper.sleep(delay);
+
+        let next = Self {
+            attempts: self.attempts - 1,
+            attempt: self.attempt + 1,
+            #[cfg(feature = "rand")]
+            prev_delay: Some(delay),
+            total_delay: self.total_delay.saturating_add(delay),
+            endpoint: match &self.endpoints {
+                Some(endpoints) => endpoints.next(self.endpoint, self.sleeper.now()),
+                None => self.endpoint,
+            },
+            ..self.clone()
         };
 
-        println!("effective delay: {}ms", delay.as_millis());
+        BackoffFuture {
+            sleep,
+            before_retry: None,
+            rewrite: None,
+            next: Some(next),
+        }
+    }
+
+    /// Delay before retrying `req` after `result`, or why it shouldn't be retried
+    fn decide<T, B, E>(
+        &self,
+        req: &Request<T>,
+        result: Result<&Response<B>, &E>,
+    ) -> Result<Duration, Stop>
+    where
+        T: Replay,
+        E: StdError + 'static,
+        C: Classify<Request<T>, Response<B>, E>,
+    {
+        let classification = self.classifier.classify(req, result);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            uri = %req.uri(),
+            attempt = self.attempt,
+            attempts_left = self.attempts,
+            status = result.ok().map(|response| response.status().as_u16()),
+            error_kind = result
+                .err()
+                .map(|err| tracing::field::debug(ErrorKind::of(err))),
+            ?classification,
+            "attempt finished"
+        );
 
-        sleep(delay).await;
+        // The circuit breaker of one endpoint doesn't stop us from failing over to another one
+        let circuit_open = result
+            .err()
+            .is_some_and(|err| ErrorKind::of(err) == ErrorKind::CircuitOpen);
+        let fail_over = circuit_open
+            && self
+                .endpoints
+                .as_ref()
+                .is_some_and(|endpoints| endpoints.len() > 1);
+        if let Some(endpoints) = &self.endpoints {
+            // Errors the classifier won't retry, such as an open circuit, still say nothing good
+            // about the endpoint
+            let healthy = result.is_ok() && classification == Classification::DontRetry;
+            endpoints.record(self.failed_endpoint(req), healthy, self.sleeper.now());
+        }
 
-        let delay = self.delay.mul_f64(self.multiplier);
-        let delay = if let Some(max_delay) = self.max_delay {
-            if delay > max_delay {
-                max_delay
-            } else {
-                delay
+        let min_delay = match classification {
+            Classification::DontRetry if fail_over => None,
+            Classification::DontRetry => {
+                if let Some(budget) = &self.budget {
+                    budget.deposit();
+                }
+                return Err(Stop::Classified);
             }
-        } else {
-            delay
+            Classification::Retry => None,
+            Classification::RetryAfter(delay) => Some(delay),
         };
-        Self {
-            attempts: self.attempts - 1,
-            delay,
-            multiplier: self.multiplier,
-            max_delay: self.max_delay,
-            jitter: self.jitter,
+
+        // Only replay non-idempotent requests if the server can deduplicate them, or if it never
+        // saw the previous attempt
+        if !is_idempotent(req.method()) && !sent_with_key(req) {
+            match result {
+                Err(err) if is_unsent(err) || circuit_open => (),
+                _ => return Err(Stop::NotIdempotent),
+            }
         }
+        // The body was too large to be kept around
+        if !req.body().can_replay() {
+            return Err(Stop::NotReplayable);
+        }
+        // Used all the attempts, stopping now
+        if self.attempts == 0 {
+            return Err(Stop::Attempts);
+        }
+        // The server may ask for a longer delay than the classifier
+        let min_delay = match result {
+            Ok(response) => min_delay.max(self.server_delay(response)),
+            Err(_) => min_delay,
+        };
+
+        let delay = self.delay(min_delay);
+        let delay = match self.remaining(req) {
+            None => delay,
+            Some(remaining) if remaining.is_zero() => return Err(Stop::Deadline),
+            // Retrying before the server is ready would be pointless
+            Some(remaining) if min_delay.is_some_and(|min_delay| min_delay > remaining) => {
+                return Err(Stop::Deadline)
+            }
+            // Make the last attempt right at the deadline
+            Some(remaining) => delay.min(remaining),
+        };
+
+        // Checked last, so that we only withdraw when actually retrying
+        if let Some(budget) = &self.budget {
+            budget.withdraw().map_err(|_| Stop::Budget)?;
+        }
+
+        Ok(delay)
+    }
+
+    /// Index of the endpoint the attempt that just failed, retried as `req`, was sent to
+    fn failed_endpoint<T>(&self, req: &Request<T>) -> usize {
+        match req.extensions().get::<Route>() {
+            // `tower::retry::RetryLayer` asks with a clone of the request it sent, made ahead of
+            // time by this policy
+            Some(route) if req.extensions().get::<Template>().is_none() => route.previous,
+            _ => self.endpoint,
+        }
+    }
+
+    /// Head of `req` as last sent, for the `before_retry` hook
+    fn head<T>(&self, req: &Request<T>) -> Request<()> {
+        let mut head = Request::new(());
+        *head.version_mut() = req.version();
+        match &self.head {
+            Some(last) => {
+                *head.uri_mut() = last.uri().clone();
+                *head.method_mut() = last.method().clone();
+                *head.headers_mut() = last.headers().clone();
+            }
+            None => {
+                *head.uri_mut() = req.uri().clone();
+                *head.method_mut() = req.method().clone();
+                *head.headers_mut() = req.headers().clone();
+            }
+        }
+        if let Some(attempt_header) = &self.attempt_header {
+            head.headers_mut().remove(&attempt_header.name);
+        }
+        head
+    }
+
+    /// Time left before `max_elapsed` is reached, if set
+    fn remaining<T>(&self, req: &Request<T>) -> Option<Duration> {
+        let max_elapsed = self.max_elapsed?;
+        let elapsed = req
+            .extensions()
+            .get::<Started>()
+            .map(|started| self.sleeper.now().saturating_duration_since(started.0))
+            .unwrap_or_default();
+        Some(max_elapsed.saturating_sub(elapsed))
+    }
+
+    /// Delay requested by the server, capped to `max_retry_after`
+    fn server_delay<B>(&self, response: &Response<B>) -> Option<Duration> {
+        retry_after(response.headers(), SystemTime::now())
+            .map(|delay| delay.min(self.max_retry_after))
     }
 }
 
@@ -108,87 +639,587 @@ impl Default for Backoff {
     fn default() -> Self {
         Self {
             attempts: 10,
+            attempt: 1,
             delay: Duration::from_millis(100),
             multiplier: 2.0,
+            schedule: None,
             max_delay: None,
+            #[cfg(feature = "rand")]
             jitter: None,
+            #[cfg(feature = "rand")]
+            prev_delay: None,
+            #[cfg(feature = "rand")]
+            rng: None,
+            delay_log: None,
+            max_retry_after: Duration::from_secs(60),
+            classifier: DefaultClassifier::default(),
+            #[cfg(feature = "rand")]
+            idempotency_key: false,
+            max_elapsed: None,
+            budget: None,
+            sleeper: TokioSleeper,
+            attempt_header: None,
+            total_delay: Duration::ZERO,
+            extensions: ExtensionRegistry::default(),
+            before_retry: None,
+            head: None,
+            endpoints: None,
+            endpoint: 0,
         }
     }
 }
 
-impl<T> Policy<Request<T>, Response<Body>, Error> for Backoff
+impl<T, B, E, C, S> Policy<Request<T>, Response<B>, E> for Backoff<C, S>
 where
-    T: Clone,
+    T: Replay,
+    E: StdError + 'static,
+    C: Classify<Request<T>, Response<B>, E> + Clone,
+    S: Sleeper + Clone,
 {
-    type Future = Pin<Box<dyn Future<Output = Self>>>;
-
-    fn retry(
-        &self,
-        req: &Request<T>,
-        result: Result<&Response<Body>, &Error>,
-    ) -> Option<Self::Future> {
-        // Used all the attempts, stopping now
-        if self.attempts <= 0 {
-            return None;
-        }
-
-        println!(
-            "calling {} ({} attempts left, {}ms delay)",
-            req.uri(),
-            self.attempts,
-            self.delay.as_millis()
-        );
+    type Future = BackoffFuture<C, S>;
 
-        match result {
-            Ok(response) => {
-                let status = response.status();
-                let new_self = self.clone();
-                // Retry on 4xx and 5xx
-                if status.is_server_error() || status.is_client_error() {
-                    Some(Box::pin(async move { new_self.next().await }))
-                } else {
-                    None
-                }
-            }
-            Err(_err) => {
-                let new_self = self.clone();
-                Some(Box::pin(async move { new_self.next().await }))
-            }
-        }
+    fn retry(&self, req: &Request<T>, result: Result<&Response<B>, &E>) -> Option<Self::Future> {
+        self.retry_or_stop(req, result).ok()
     }
 
     fn clone_request(&self, req: &Request<T>) -> Option<Request<T>> {
         // `Request` can't be cloned
+        // The `before_retry` hook may have rewritten the request
+        let (uri, method, headers) = match &self.head {
+            Some(head) => (head.uri(), head.method(), head.headers()),
+            None => (req.uri(), req.method(), req.headers()),
+        };
+        let uri = match &self.endpoints {
+            Some(endpoints) => endpoints.uri(uri, self.endpoint)?,
+            None => uri.clone(),
+        };
         let mut new_req = Request::builder()
-            .uri(req.uri())
-            .method(req.method())
+            .uri(uri)
+            .method(method)
             .version(req.version());
-        for (name, value) in req.headers() {
+        for (name, value) in headers {
+            // Set by the client from the URI, which may now point to another endpoint
+            if self.endpoints.is_some() && name == HOST {
+                continue;
+            }
+            if self
+                .attempt_header
+                .as_ref()
+                .is_some_and(|attempt_header| attempt_header.name == name)
+            {
+                continue;
+            }
             new_req = new_req.header(name, value);
         }
-        let body = req.body().clone();
-        let new_req = new_req.body(body).expect("failed to build request");
+        if let Some(attempt_header) = &self.attempt_header {
+            let value = if attempt_header.delay {
+                format!("{}; delay={}ms", self.attempt, self.total_delay.as_millis())
+            } else {
+                self.attempt.to_string()
+            };
+            new_req = new_req.header(&attempt_header.name, value);
+        }
+        #[cfg(feature = "rand")]
+        let generated_key = self.idempotency_key && !headers.contains_key(IDEMPOTENCY_KEY);
+        #[cfg(feature = "rand")]
+        if generated_key {
+            new_req = new_req.header(IDEMPOTENCY_KEY, generate_key());
+        }
+        let body = req.body().replay()?;
+        let mut new_req = new_req.body(body).ok()?;
+
+        let extensions = new_req.extensions_mut();
+        #[cfg(feature = "rand")]
+        if generated_key {
+            extensions.insert(GeneratedKey);
+        }
+        self.extensions.copy(req.extensions(), extensions);
+        // Carried over so that `max_elapsed` is measured from the first attempt
+        extensions.insert(
+            req.extensions()
+                .get::<Started>()
+                .copied()
+                .unwrap_or_else(|| Started(self.sleeper.now())),
+        );
+        extensions.insert(Attempt(self.attempt));
+        if self.endpoints.is_some() {
+            extensions.insert(Route {
+                endpoint: self.endpoint,
+                previous: req
+                    .extensions()
+                    .get::<Route>()
+                    .map_or(0, |route| route.endpoint),
+            });
+        }
 
         Some(new_req)
     }
 }
 
+impl<T, B, E, C, S> RetryPolicy<Request<T>, Response<B>, E> for Backoff<C, S>
+where
+    T: Replay,
+    E: StdError + 'static,
+    C: Classify<Request<T>, Response<B>, E> + Clone,
+    S: Sleeper + Clone,
+{
+    fn retry_or_stop(
+        &self,
+        req: &Request<T>,
+        result: Result<&Response<B>, &E>,
+    ) -> Result<Self::Future, Stop> {
+        let decision = self.decide(req, result);
+        #[cfg(feature = "tracing")]
+        if let Err(stop) = decision {
+            tracing::debug!(?stop, "not retrying");
+        }
+        #[cfg(feature = "metrics")]
+        metrics::record(req, result, self.attempt, decision);
+        decision.map(|delay| {
+            let mut future = self.next(delay);
+            if let Some(before_retry) = &self.before_retry {
+                future.before_retry = Some(PendingHook {
+                    before_retry: before_retry.clone(),
+                    head: self.head(req),
+                    outcome: Outcome::of(result),
+                    attempt: self.attempt + 1,
+                });
+            }
+            future
+        })
+    }
+}
+
+/// Number of the attempt a request is sent for, starting at 1
+///
+/// This is added to the extensions of each request built by [`Backoff`], for the layers between
+/// the [`RetryLayer`](crate::retry::RetryLayer) and the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Attempt(pub usize);
+
+/// Why [`Backoff`] stopped retrying a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The classifier didn't ask for a retry
+    Classified,
+    /// The request isn't idempotent, and the server may have seen the previous attempt
+    NotIdempotent,
+    /// The body was too large to be kept for replays
+    NotReplayable,
+    /// All the attempts were used
+    Attempts,
+    /// The next attempt couldn't start before `max_elapsed`
+    Deadline,
+    /// The retry budget was empty
+    Budget,
+}
+
+/// Delays chosen by a [`Backoff`] before each retry, in order
+///
+/// Clones share the same log, so one can be kept to read the delays back while the policy is in
+/// use.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Default)]
+pub struct DelayLog(Arc<Mutex<Vec<Duration>>>);
+
+#[allow(dead_code)]
+impl DelayLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays recorded so far
+    pub fn delays(&self) -> Vec<Duration> {
+        self.0.lock().expect("delay log lock poisoned").clone()
+    }
+
+    fn record(&self, delay: Duration) {
+        self.0.lock().expect("delay log lock poisoned").push(delay);
+    }
+}
+
+/// Default name of the header added by [`Backoff::with_attempt_header`]
+pub const RETRY_ATTEMPT: HeaderName = HeaderName::from_static("x-retry-attempt");
+
+/// Header carrying the attempt number, see [`Backoff::with_attempt_header`]
+#[derive(Clone, Debug)]
+struct AttemptHeader {
+    name: HeaderName,
+    /// Also carry the total delay waited so far
+    delay: bool,
+}
+
+impl Default for AttemptHeader {
+    fn default() -> Self {
+        Self {
+            name: RETRY_ATTEMPT,
+            delay: false,
+        }
+    }
+}
+
+/// When the first attempt of a request started
+#[derive(Clone, Copy, Debug)]
+struct Started(Instant);
+
+/// Endpoints of a request built by `clone_request` and of the one it was cloned from
+#[derive(Clone, Copy, Debug)]
+struct Route {
+    endpoint: usize,
+    /// The caller's request is sent to the primary endpoint
+    previous: usize,
+}
+
+/// Marks a request whose `Idempotency-Key` was generated by `clone_request`
+///
+/// The request it was cloned from didn't carry the key, so neither did the attempt that just
+/// failed, unless the request is the [`Template`] of this crate's layer.
+#[cfg(feature = "rand")]
+#[derive(Clone, Copy, Debug)]
+struct GeneratedKey;
+
+/// Whether the attempt that just failed, retried as `req`, was sent with an `Idempotency-Key`
+fn sent_with_key<T>(req: &Request<T>) -> bool {
+    if !req.headers().contains_key(IDEMPOTENCY_KEY) {
+        return false;
+    }
+    #[cfg(feature = "rand")]
+    if req.extensions().get::<GeneratedKey>().is_some() {
+        return req.extensions().get::<Template>().is_some();
+    }
+    true
+}
+
+pin_project! {
+    /// Wait for the delay before the next attempt
+    ///
+    /// This resolves to the policy to use for that attempt.
+    pub struct BackoffFuture<C, S>
+    where
+        S: Sleeper,
+    {
+        #[pin]
+        sleep: S::Sleep,
+        // Called once the delay is over
+        before_retry: Option<PendingHook>,
+        // Runs the `before_retry` hook
+        rewrite: Option<Rewrite>,
+        next: Option<Backoff<C, S>>,
+    }
+}
+
+impl<C, S: Sleeper> Future for BackoffFuture<C, S> {
+    type Output = Backoff<C, S>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        ready!(this.sleep.poll(cx));
+        if let Some(hook) = this.before_retry.take() {
+            *this.rewrite = Some(hook.call());
+        }
+        if let Some(rewrite) = this.rewrite {
+            let head = ready!(rewrite.as_mut().poll(cx));
+            *this.rewrite = None;
+            if let Some(next) = this.next {
+                next.head = Some(Arc::new(head));
+            }
+        }
+        Poll::Ready(this.next.take().expect("polled after completion"))
+    }
+}
+
+/// Call to the `before_retry` hook, made by [`BackoffFuture`] once the delay is over
+struct PendingHook {
+    before_retry: Arc<dyn BeforeRetry>,
+    head: Request<()>,
+    outcome: Outcome,
+    attempt: usize,
+}
+
+impl PendingHook {
+    fn call(self) -> Rewrite {
+        self.before_retry
+            .before_retry(self.head, self.outcome, self.attempt)
+    }
+}
+
+/// `delay` multiplied by `factor`, saturating to `Duration::MAX`
+#[cfg(feature = "rand")]
+fn scale(delay: Duration, factor: f64) -> Duration {
+    Duration::try_from_secs_f64(delay.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+}
+
+/// Randomization of the delays between attempts
+///
+/// `Full`, `Equal` and `Decorrelated` are the strategies described in
+/// <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+#[allow(dead_code)]
 #[derive(Clone, Debug, Copy)]
 pub enum Jitter {
     /// Maximum jitter duration to add to delays between attempts
     Duration(Duration),
     /// Maximum percentage of jitter to add to delays between attempts
     Percentage(f64),
+    /// Maximum jitter duration to add to or remove from delays between attempts
+    SymmetricDuration(Duration),
+    /// Maximum percentage of jitter to add to or remove from delays between attempts
+    ///
+    /// This is capped to 1.0, so that delays can't become negative.
+    SymmetricPercentage(f64),
+    /// Random delay between zero and the delay
+    Full,
+    /// Half the delay, plus a random delay between zero and the other half
+    Equal,
+    /// Random delay between the first delay and three times the previous delay
+    ///
+    /// This only uses the first delay of the schedule, but is capped to the maximum delay.
+    Decorrelated,
+}
+
+impl From<f64> for Jitter {
+    fn from(percentage: f64) -> Self {
+        Jitter::Percentage(percentage)
+    }
 }
 
-impl Into<Jitter> for f64 {
-    fn into(self) -> Jitter {
-        Jitter::Percentage(self)
+impl From<Duration> for Jitter {
+    fn from(duration: Duration) -> Self {
+        Jitter::Duration(duration)
     }
 }
 
-impl Into<Jitter> for Duration {
-    fn into(self) -> Jitter {
-        Jitter::Duration(self)
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_util::FutureExt;
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+    use crate::{
+        mock::{Mock, Seen},
+        retry::RetryLayer,
+        sleep::{Clock, ManualClock},
+    };
+
+    type TestBackoff = Backoff<DefaultClassifier, ManualClock>;
+
+    fn backoff() -> TestBackoff {
+        Backoff::new().with_sleeper(ManualClock::new())
+    }
+
+    /// Move `policy` on to the next attempt after a 503 response to `req`
+    fn retry(policy: &TestBackoff, req: &Request<()>) -> Option<TestBackoff> {
+        let response = Response::builder().status(503).body(()).unwrap();
+        let future = Policy::<_, _, io::Error>::retry(policy, req, Ok(&response))?;
+        Some(future.now_or_never().expect("manual sleeps are ready"))
+    }
+
+    /// Retry until `policy` stops, returning the delays it chose
+    fn delays(mut policy: TestBackoff) -> Vec<Duration> {
+        let log = DelayLog::new();
+        policy = policy.with_delay_log(log.clone());
+        let req = Policy::<_, Response<()>, io::Error>::clone_request(&policy, &Request::new(()))
+            .unwrap();
+        while let Some(next) = retry(&policy, &req) {
+            policy = next;
+        }
+        log.delays()
+    }
+
+    #[test]
+    fn delay_log_records_each_delay() {
+        let policy = backoff()
+            .with_attempts(4)
+            .with_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(300));
+        assert_eq!(
+            delays(policy),
+            [100, 200, 300, 300].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn delay_log_is_shared_by_clones() {
+        let log = DelayLog::new();
+        let policy = backoff().with_delay_log(log.clone());
+        let req = Request::new(());
+        let next = retry(&policy.clone(), &req).unwrap();
+        retry(&next, &req).unwrap();
+        assert_eq!(log.delays(), [100, 200].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn manual_clock_records_sleeps() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let policy = backoff().with_attempts(3).with_sleeper(clock.clone());
+        let delays = delays(policy);
+        assert_eq!(clock.sleeps(), delays);
+        assert_eq!(clock.now() - start, Duration::from_millis(700));
+    }
+
+    #[test]
+    fn max_elapsed_follows_manual_clock() {
+        let clock = ManualClock::new();
+        let policy = backoff()
+            .with_max_elapsed(Duration::from_millis(1000))
+            .with_sleeper(clock.clone());
+        // The last attempt starts right at the deadline
+        assert_eq!(
+            delays(policy),
+            [100, 200, 400, 300].map(Duration::from_millis)
+        );
+
+        // Time spent outside of the delays counts too
+        let policy = backoff()
+            .with_max_elapsed(Duration::from_millis(1000))
+            .with_sleeper(clock.clone());
+        let req = Policy::<_, Response<()>, io::Error>::clone_request(&policy, &Request::new(()))
+            .unwrap();
+        clock.advance(Duration::from_millis(950));
+        let next = retry(&policy, &req).unwrap();
+        assert_eq!(clock.sleeps().last(), Some(&Duration::from_millis(50)));
+        assert!(retry(&next, &req).is_none());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn same_seed_same_delays() {
+        let policy = |seed| backoff().with_jitter(Jitter::Full).with_seed(seed);
+        let delays_42 = delays(policy(42));
+        assert_eq!(delays_42.len(), 10);
+        assert_eq!(delays(policy(42)), delays_42);
+        assert_ne!(delays(policy(7)), delays_42);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn seeded_delays_stay_within_jitter() {
+        let policy = backoff()
+            .with_delay(Duration::from_millis(100))
+            .with_jitter(Jitter::Equal)
+            .with_seed(1);
+        for (retry, delay) in delays(policy).into_iter().enumerate() {
+            let scheduled = Duration::from_millis(100) * 2u32.pow(retry as u32);
+            assert!(delay >= scheduled / 2 && delay <= scheduled, "{delay:?}");
+        }
+    }
+
+    /// Every jitter strategy on a schedule that saturated to `Duration::MAX`
+    #[cfg(feature = "rand")]
+    #[tokio::test]
+    async fn jitter_saturates() {
+        let saturated = crate::schedule::Linear {
+            initial: Duration::MAX,
+            increment: Duration::from_secs(1),
+        };
+        let req = Request::new(());
+        let response = Response::builder().status(503).body(()).unwrap();
+        for jitter in [
+            Jitter::Duration(Duration::from_secs(1)),
+            Jitter::Percentage(0.1),
+            Jitter::SymmetricDuration(Duration::from_secs(1)),
+            Jitter::SymmetricPercentage(0.5),
+            Jitter::Full,
+            Jitter::Equal,
+            Jitter::Decorrelated,
+        ] {
+            let log = DelayLog::new();
+            let policy = Backoff::new()
+                .with_schedule(saturated)
+                .with_jitter(jitter)
+                .with_delay_log(log.clone());
+            let next = Policy::<_, _, io::Error>::retry(&policy, &req, Ok(&response));
+            assert!(next.is_some(), "{jitter:?}");
+            if !matches!(jitter, Jitter::Full) {
+                assert!(log.delays()[0] > Duration::MAX / 4, "{jitter:?}");
+            }
+        }
+    }
+
+    #[cfg(feature = "rand")]
+    #[tokio::test]
+    async fn default_schedule_saturates() {
+        let log = DelayLog::new();
+        let policy = Backoff {
+            attempt: 100,
+            ..Backoff::new()
+                .with_jitter(Jitter::Percentage(0.1))
+                .with_delay_log(log.clone())
+        };
+        let req = Request::new(());
+        let response = Response::builder().status(503).body(()).unwrap();
+        assert!(Policy::<_, _, io::Error>::retry(&policy, &req, Ok(&response)).is_some());
+        assert_eq!(log.delays(), [Duration::MAX]);
+    }
+
+    /// Values of the `name` header of each request in `seen`
+    fn header_values(seen: &[Seen], name: &str) -> Vec<Vec<String>> {
+        seen.iter()
+            .map(|seen| {
+                let values = seen.headers.get_all(name).iter();
+                values
+                    .map(|value| value.to_str().unwrap().to_owned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn attempt_header_counts_attempts() {
+        let mock = Mock::new().status(503).status(503);
+        let policy = backoff().with_attempt_header();
+        let req = Request::get("http://localhost/").body(()).unwrap();
+        RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(
+            header_values(&mock.requests(), "x-retry-attempt"),
+            [["1"], ["2"], ["3"]]
+        );
+    }
+
+    #[tokio::test]
+    async fn attempt_header_with_delay() {
+        let mock = Mock::new().status(503).status(503);
+        let policy = backoff().with_attempt_header_delay();
+        let req = Request::get("http://localhost/").body(()).unwrap();
+        RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(
+            header_values(&mock.requests(), "x-retry-attempt"),
+            [["1; delay=0ms"], ["2; delay=100ms"], ["3; delay=300ms"]]
+        );
+    }
+
+    #[tokio::test]
+    async fn attempt_header_replaces_caller_value() {
+        let mock = Mock::new().status(503);
+        let policy = backoff().with_attempt_header_name(HeaderName::from_static("x-attempt"));
+        let req = Request::get("http://localhost/")
+            .header("x-attempt", "7")
+            .header("x-attempt", "8")
+            .header(RETRY_ATTEMPT, "kept")
+            .body(())
+            .unwrap();
+        RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+
+        let seen = mock.requests();
+        assert_eq!(header_values(&seen, "x-attempt"), [["1"], ["2"]]);
+        // Only the configured name is replaced
+        assert_eq!(
+            header_values(&seen, "x-retry-attempt"),
+            [["kept"], ["kept"]]
+        );
     }
 }
@@ -0,0 +1,314 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use hyper::{
+    body::{Buf, Bytes, HttpBody, SizeHint},
+    Body, HeaderMap,
+};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Request body that can be sent again on a retry
+pub trait Replay: Sized {
+    /// Return a copy of the body for another attempt, if possible
+    fn replay(&self) -> Option<Self>;
+
+    /// Whether the body can still be replayed
+    ///
+    /// This lets the policy give up before waiting for the next attempt.
+    fn can_replay(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Clone> Replay for T {
+    fn replay(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+}
+
+/// Streaming body that keeps the bytes it sends, so that it can be replayed
+///
+/// All the copies of a `ReplayBody` share the same underlying stream: the first one to be polled
+/// reads from it and stores the chunks in a buffer, and the other copies read from that buffer.
+/// Once more than `limit` bytes have been read, the buffer is dropped and the body can only be
+/// sent once.
+///
+/// Trailers are not forwarded.
+pub struct ReplayBody<B = Body> {
+    shared: Arc<Mutex<Shared<B>>>,
+    /// Index of the next chunk to read from the buffer
+    position: usize,
+    /// Underlying stream, once this copy is the only one that can read it
+    exclusive: Option<B>,
+}
+
+struct Shared<B> {
+    body: Option<B>,
+    chunks: Vec<Bytes>,
+    len: usize,
+    limit: usize,
+    state: State,
+    /// Copies waiting for another one to read the next chunk
+    wakers: Vec<Waker>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Streaming,
+    Complete,
+    /// The body went over the limit, or the underlying stream failed
+    Unavailable,
+}
+
+impl<B> ReplayBody<B> {
+    /// Wrap `body`, buffering up to `limit` bytes for replays
+    pub fn new(body: B, limit: usize) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                body: Some(body),
+                chunks: Vec::new(),
+                len: 0,
+                limit,
+                state: State::Streaming,
+                wakers: Vec::new(),
+            })),
+            position: 0,
+            exclusive: None,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Shared<B>> {
+        self.shared.lock().expect("replay body lock poisoned")
+    }
+}
+
+impl<B> Shared<B> {
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<B> Replay for ReplayBody<B> {
+    fn replay(&self) -> Option<Self> {
+        if !self.can_replay() {
+            return None;
+        }
+        Some(Self {
+            shared: self.shared.clone(),
+            position: 0,
+            exclusive: None,
+        })
+    }
+
+    fn can_replay(&self) -> bool {
+        self.lock().state != State::Unavailable
+    }
+}
+
+impl<B> HttpBody for ReplayBody<B>
+where
+    B: HttpBody + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(body) = this.exclusive.as_mut() {
+            return Pin::new(body)
+                .poll_data(cx)
+                .map_ok(|mut data| data.copy_to_bytes(data.remaining()))
+                .map_err(Into::into);
+        }
+
+        let mut shared = this.shared.lock().expect("replay body lock poisoned");
+        if let Some(chunk) = shared.chunks.get(this.position) {
+            this.position += 1;
+            return Poll::Ready(Some(Ok(chunk.clone())));
+        }
+        match shared.state {
+            State::Complete => return Poll::Ready(None),
+            State::Unavailable => return Poll::Ready(Some(Err(Unavailable.into()))),
+            State::Streaming => (),
+        }
+
+        let body = shared.body.as_mut().expect("streaming without a body");
+        match Pin::new(body).poll_data(cx) {
+            Poll::Pending => {
+                if !shared
+                    .wakers
+                    .iter()
+                    .any(|waker| waker.will_wake(cx.waker()))
+                {
+                    shared.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Poll::Ready(None) => {
+                shared.state = State::Complete;
+                shared.body = None;
+                shared.wake_all();
+                Poll::Ready(None)
+            }
+            Poll::Ready(Some(Err(err))) => {
+                shared.state = State::Unavailable;
+                shared.body = None;
+                shared.chunks.clear();
+                shared.wake_all();
+                Poll::Ready(Some(Err(err.into())))
+            }
+            Poll::Ready(Some(Ok(mut data))) => {
+                let chunk = data.copy_to_bytes(data.remaining());
+                if shared.len + chunk.len() > shared.limit {
+                    // Keep streaming for this copy only
+                    shared.state = State::Unavailable;
+                    this.exclusive = shared.body.take();
+                    shared.chunks.clear();
+                } else {
+                    shared.len += chunk.len();
+                    shared.chunks.push(chunk.clone());
+                    this.position = shared.chunks.len();
+                }
+                shared.wake_all();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        if let Some(body) = self.exclusive.as_ref() {
+            return body.is_end_stream();
+        }
+        let shared = self.lock();
+        shared.state == State::Complete && self.position >= shared.chunks.len()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if let Some(body) = self.exclusive.as_ref() {
+            return body.size_hint();
+        }
+        let shared = self.lock();
+        let buffered: usize = shared.chunks[self.position.min(shared.chunks.len())..]
+            .iter()
+            .map(Bytes::len)
+            .sum();
+        let buffered = buffered as u64;
+        match (shared.state, shared.body.as_ref()) {
+            (State::Streaming, Some(body)) => {
+                let hint = body.size_hint();
+                let mut size_hint = SizeHint::new();
+                size_hint.set_lower(hint.lower() + buffered);
+                if let Some(upper) = hint.upper() {
+                    size_hint.set_upper(upper + buffered);
+                }
+                size_hint
+            }
+            _ => SizeHint::with_exact(buffered),
+        }
+    }
+}
+
+/// Error returned when reading a body that can no longer be replayed
+#[derive(Debug)]
+pub struct Unavailable;
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request body can no longer be replayed")
+    }
+}
+
+impl StdError for Unavailable {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use hyper::body::to_bytes;
+
+    use super::*;
+
+    /// Body that is always ready with the next of its chunks
+    struct Chunks(VecDeque<Bytes>);
+
+    impl Chunks {
+        fn new(chunks: &[&'static str]) -> Self {
+            Self(chunks.iter().map(|chunk| Bytes::from(*chunk)).collect())
+        }
+    }
+
+    impl HttpBody for Chunks {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_from_buffer() {
+        let body = ReplayBody::new(Chunks::new(&["hello", " ", "world"]), 64);
+        let copy = body.replay().unwrap();
+        assert_eq!(to_bytes(body).await.unwrap(), "hello world");
+        assert!(copy.can_replay());
+        let again = copy.replay().unwrap();
+        assert_eq!(to_bytes(copy).await.unwrap(), "hello world");
+        assert_eq!(to_bytes(again).await.unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn over_limit_is_sent_once() {
+        let body = ReplayBody::new(Chunks::new(&["hello", "world", "!"]), 8);
+        let copy = body.replay().unwrap();
+        assert_eq!(to_bytes(body).await.unwrap(), "helloworld!");
+        assert!(!copy.can_replay());
+        assert!(copy.replay().is_none());
+        assert!(to_bytes(copy).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn copies_polled_at_once() {
+        let (mut sender, inner) = Body::channel();
+        let body = ReplayBody::new(inner, 64);
+        let copy = body.replay().unwrap();
+        let first = tokio::spawn(to_bytes(body));
+        let second = tokio::spawn(to_bytes(copy));
+
+        sender.send_data(Bytes::from("one")).await.unwrap();
+        sender.send_data(Bytes::from("two")).await.unwrap();
+        drop(sender);
+
+        assert_eq!(first.await.unwrap().unwrap(), "onetwo");
+        assert_eq!(second.await.unwrap().unwrap(), "onetwo");
+    }
+}
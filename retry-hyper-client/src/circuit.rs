@@ -0,0 +1,587 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use hyper::{Request, Response, Uri};
+use pin_project_lite::pin_project;
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+use crate::classify::{Classification, Classify, DefaultClassifier};
+
+/// Stop sending requests to an authority (host and port) that keeps failing
+///
+/// Results are classified with the same [`Classify`] trait as [`Backoff`](crate::backoff::Backoff):
+/// anything that would be retried counts as a failure. While the circuit is open, requests fail
+/// right away with [`CircuitError::Open`], which the default classifier does not retry.
+///
+/// Once `cooldown` has elapsed, the circuit becomes half-open and lets up to `probes` requests
+/// through. The circuit closes if they all succeed, and opens again on the first failure.
+///
+/// All the services created by a layer, and their clones, share the same circuits.
+#[derive(Clone)]
+pub struct CircuitBreakerLayer<C = DefaultClassifier> {
+    config: Config,
+    classifier: C,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    /// Open after this many consecutive failures
+    consecutive_failures: Option<usize>,
+    /// Open when the ratio of failures over the last `window` results reaches `failure_rate`
+    failure_rate: Option<(f64, usize)>,
+    /// How long the circuit stays open
+    cooldown: Duration,
+    /// Number of requests allowed through when half-open
+    probes: usize,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for CircuitBreakerLayer {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                consecutive_failures: Some(5),
+                failure_rate: None,
+                cooldown: Duration::from_secs(30),
+                probes: 1,
+            },
+            classifier: DefaultClassifier::default(),
+            circuits: Default::default(),
+        }
+    }
+}
+
+impl<C> CircuitBreakerLayer<C> {
+    /// Open after `failures` failures in a row, at least 1
+    #[allow(dead_code)]
+    pub fn with_consecutive_failures(mut self, failures: usize) -> Self {
+        self.config.consecutive_failures = Some(failures.max(1));
+        self
+    }
+
+    /// Open when at least `rate` of the last `window` results are failures
+    ///
+    /// `rate` is clamped above 0.0, so that at least one failure is needed, and up to 1.0. The
+    /// window holds at least 1 result.
+    #[allow(dead_code)]
+    pub fn with_failure_rate(mut self, rate: f64, window: usize) -> Self {
+        let rate = if rate.is_nan() {
+            1.0
+        } else {
+            rate.clamp(f64::MIN_POSITIVE, 1.0)
+        };
+        self.config.failure_rate = Some((rate, window.max(1)));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.config.cooldown = cooldown;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_probes(mut self, probes: usize) -> Self {
+        self.config.probes = probes.max(1);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_classifier<D>(self, classifier: D) -> CircuitBreakerLayer<D> {
+        CircuitBreakerLayer {
+            config: self.config,
+            classifier,
+            circuits: self.circuits,
+        }
+    }
+}
+
+impl<S, C> Layer<S> for CircuitBreakerLayer<C>
+where
+    C: Clone,
+{
+    type Service = CircuitBreaker<S, C>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CircuitBreaker {
+            config: self.config,
+            classifier: self.classifier.clone(),
+            circuits: self.circuits.clone(),
+            service,
+        }
+    }
+}
+
+/// Service created by [`CircuitBreakerLayer`]
+#[derive(Clone)]
+pub struct CircuitBreaker<S, C = DefaultClassifier> {
+    config: Config,
+    classifier: C,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+    service: S,
+}
+
+impl<S, C, T, B> Service<Request<T>> for CircuitBreaker<S, C>
+where
+    S: Service<Request<T>, Response = Response<B>>,
+    C: Classify<Request<()>, Response<B>, S::Error> + Clone,
+{
+    type Response = S::Response;
+    type Error = CircuitError<S::Error>;
+    type Future = ResponseFuture<S::Future, C>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(CircuitError::Inner)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        let authority = authority(req.uri());
+        let probe = {
+            let mut circuits = self.circuits.lock().expect("circuit lock poisoned");
+            let circuit = circuits.entry(authority.clone()).or_default();
+            match circuit.acquire(&self.config) {
+                Ok(probe) => probe,
+                Err(retry_in) => {
+                    return ResponseFuture::Open {
+                        error: Some(Open {
+                            authority,
+                            retry_in,
+                        }),
+                    }
+                }
+            }
+        };
+
+        // Keep what the classifier may need from the request
+        let mut head = Request::new(());
+        *head.method_mut() = req.method().clone();
+        *head.uri_mut() = req.uri().clone();
+        *head.version_mut() = req.version();
+        *head.headers_mut() = req.headers().clone();
+
+        ResponseFuture::Called {
+            future: self.service.call(req),
+            permit: Permit {
+                circuits: self.circuits.clone(),
+                config: self.config,
+                authority,
+                probe,
+                done: false,
+            },
+            classifier: self.classifier.clone(),
+            head,
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`CircuitBreaker`]
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F, C> {
+        Called {
+            #[pin]
+            future: F,
+            permit: Permit,
+            classifier: C,
+            head: Request<()>,
+        },
+        Open {
+            error: Option<Open>,
+        },
+    }
+}
+
+impl<F, C, B, E> Future for ResponseFuture<F, C>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    C: Classify<Request<()>, Response<B>, E>,
+{
+    type Output = Result<Response<B>, CircuitError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Called {
+                future,
+                permit,
+                classifier,
+                head,
+            } => {
+                let result = ready!(future.poll(cx));
+                let failed = !matches!(
+                    classifier.classify(head, result.as_ref()),
+                    Classification::DontRetry
+                );
+                permit.record(failed);
+                Poll::Ready(result.map_err(CircuitError::Inner))
+            }
+            ResponseFutureProj::Open { error } => Poll::Ready(Err(CircuitError::Open(
+                error.take().expect("polled after completion"),
+            ))),
+        }
+    }
+}
+
+/// Permission to send a request, used to record its result
+pub struct Permit {
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+    config: Config,
+    authority: String,
+    probe: bool,
+    done: bool,
+}
+
+impl Permit {
+    fn record(&mut self, failed: bool) {
+        self.done = true;
+        let mut circuits = self.circuits.lock().expect("circuit lock poisoned");
+        if let Some(circuit) = circuits.get_mut(&self.authority) {
+            circuit.record(&self.config, self.probe, failed);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // A probe that was canceled must not hold its slot forever
+        if self.probe && !self.done {
+            let mut circuits = self.circuits.lock().expect("circuit lock poisoned");
+            if let Some(Circuit {
+                state: State::HalfOpen { in_flight, .. },
+                ..
+            }) = circuits.get_mut(&self.authority)
+            {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    state: State,
+    consecutive_failures: usize,
+    /// Latest results, `true` for failures
+    window: VecDeque<bool>,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Closed,
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        in_flight: usize,
+        successes: usize,
+    },
+}
+
+impl Circuit {
+    /// Let a request through, returning whether it is a probe, or how long until the next probe
+    fn acquire(&mut self, config: &Config) -> Result<bool, Duration> {
+        let now = Instant::now();
+        if let State::Open { until } = self.state {
+            if until > now {
+                return Err(until - now);
+            }
+            self.state = State::HalfOpen {
+                in_flight: 0,
+                successes: 0,
+            };
+        }
+
+        match &mut self.state {
+            State::Closed => Ok(false),
+            State::HalfOpen { in_flight, .. } if *in_flight < config.probes => {
+                *in_flight += 1;
+                Ok(true)
+            }
+            // Wait for the probes to complete
+            State::HalfOpen { .. } => Err(Duration::ZERO),
+            State::Open { .. } => unreachable!("open circuits are handled above"),
+        }
+    }
+
+    fn record(&mut self, config: &Config, probe: bool, failed: bool) {
+        match &mut self.state {
+            State::HalfOpen {
+                in_flight,
+                successes,
+            } if probe => {
+                *in_flight = in_flight.saturating_sub(1);
+                if failed {
+                    self.open(config);
+                } else {
+                    *successes += 1;
+                    if *successes >= config.probes {
+                        self.state = State::Closed;
+                    }
+                }
+            }
+            State::Closed => {
+                if failed {
+                    self.consecutive_failures += 1;
+                } else {
+                    self.consecutive_failures = 0;
+                }
+                if let Some((_, window)) = config.failure_rate {
+                    self.window.push_back(failed);
+                    if self.window.len() > window {
+                        self.window.pop_front();
+                    }
+                }
+
+                if self.should_open(config) {
+                    self.open(config);
+                }
+            }
+            // Results of requests sent before the circuit opened
+            _ => (),
+        }
+    }
+
+    fn should_open(&self, config: &Config) -> bool {
+        if let Some(failures) = config.consecutive_failures {
+            if self.consecutive_failures >= failures {
+                return true;
+            }
+        }
+        if let Some((rate, window)) = config.failure_rate {
+            if self.window.len() >= window {
+                let failures = self.window.iter().filter(|failed| **failed).count();
+                return failures as f64 >= rate * self.window.len() as f64;
+            }
+        }
+        false
+    }
+
+    fn open(&mut self, config: &Config) {
+        self.state = State::Open {
+            until: Instant::now() + config.cooldown,
+        };
+        self.consecutive_failures = 0;
+        self.window.clear();
+    }
+}
+
+/// Host and port of a URI, with the default port for its scheme
+pub(crate) fn authority(uri: &Uri) -> String {
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    format!("{host}:{port}")
+}
+
+/// Error returned by [`CircuitBreaker`]
+#[derive(Debug)]
+pub enum CircuitError<E> {
+    /// The circuit is open for this authority
+    Open(Open),
+    /// The inner service failed
+    Inner(E),
+}
+
+impl<E> fmt::Display for CircuitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(_) => f.write_str("request rejected by the circuit breaker"),
+            Self::Inner(_) => f.write_str("request failed"),
+        }
+    }
+}
+
+impl<E> StdError for CircuitError<E>
+where
+    E: StdError + 'static,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Open(err) => Some(err),
+            Self::Inner(err) => Some(err),
+        }
+    }
+}
+
+/// The circuit is open, so the request was not sent
+#[derive(Clone, Debug)]
+pub struct Open {
+    authority: String,
+    retry_in: Duration,
+}
+
+impl Open {
+    /// Time until the circuit lets requests through again
+    #[allow(dead_code)]
+    pub fn retry_in(&self) -> Duration {
+        self.retry_in
+    }
+}
+
+impl fmt::Display for Open {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "circuit open for {} (retry in {}ms)",
+            self.authority,
+            self.retry_in.as_millis()
+        )
+    }
+}
+
+impl StdError for Open {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(20);
+
+    fn config(layer: CircuitBreakerLayer) -> Config {
+        layer.with_cooldown(COOLDOWN).config
+    }
+
+    fn is_open(circuit: &Circuit) -> bool {
+        matches!(circuit.state, State::Open { .. })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let config = config(CircuitBreakerLayer::new().with_consecutive_failures(2));
+        let mut circuit = Circuit::default();
+
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, true);
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, false);
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, true);
+        assert!(!is_open(&circuit));
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, true);
+
+        assert!(is_open(&circuit));
+        let retry_in = circuit.acquire(&config).unwrap_err();
+        assert!(retry_in > Duration::ZERO && retry_in <= COOLDOWN);
+    }
+
+    #[test]
+    fn opens_on_failure_rate() {
+        let config = config(
+            CircuitBreakerLayer::new()
+                .with_consecutive_failures(usize::MAX)
+                .with_failure_rate(0.5, 4),
+        );
+        let mut circuit = Circuit::default();
+
+        for failed in [true, false, true] {
+            assert_eq!(circuit.acquire(&config), Ok(false));
+            circuit.record(&config, false, failed);
+            assert!(!is_open(&circuit));
+        }
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, false);
+        assert!(is_open(&circuit));
+    }
+
+    #[test]
+    fn half_open_closes_after_probes() {
+        let config = config(
+            CircuitBreakerLayer::new()
+                .with_consecutive_failures(1)
+                .with_probes(2),
+        );
+        let mut circuit = Circuit::default();
+        circuit.record(&config, false, true);
+        assert!(is_open(&circuit));
+
+        thread::sleep(COOLDOWN);
+        assert_eq!(circuit.acquire(&config), Ok(true));
+        assert_eq!(circuit.acquire(&config), Ok(true));
+        // Both probes are in flight
+        assert_eq!(circuit.acquire(&config), Err(Duration::ZERO));
+
+        circuit.record(&config, true, false);
+        assert!(matches!(circuit.state, State::HalfOpen { .. }));
+        circuit.record(&config, true, false);
+        assert!(matches!(circuit.state, State::Closed));
+        assert_eq!(circuit.acquire(&config), Ok(false));
+    }
+
+    #[test]
+    fn half_open_reopens_on_failure() {
+        let config = config(CircuitBreakerLayer::new().with_consecutive_failures(1));
+        let mut circuit = Circuit::default();
+        circuit.record(&config, false, true);
+
+        thread::sleep(COOLDOWN);
+        assert_eq!(circuit.acquire(&config), Ok(true));
+        circuit.record(&config, true, true);
+        assert!(is_open(&circuit));
+        assert!(circuit.acquire(&config).is_err());
+    }
+
+    #[test]
+    fn failure_rate_is_clamped() {
+        let mut circuit = Circuit::default();
+        let zero = config(
+            CircuitBreakerLayer::new()
+                .with_consecutive_failures(usize::MAX)
+                .with_failure_rate(0.0, 2),
+        );
+        for _ in 0..4 {
+            circuit.record(&zero, false, false);
+        }
+        assert!(!is_open(&circuit));
+        circuit.record(&zero, false, true);
+        assert!(is_open(&circuit));
+
+        for rate in [f64::NAN, 2.0] {
+            let mut circuit = Circuit::default();
+            let clamped = config(
+                CircuitBreakerLayer::new()
+                    .with_consecutive_failures(usize::MAX)
+                    .with_failure_rate(rate, 2),
+            );
+            circuit.record(&clamped, false, true);
+            circuit.record(&clamped, false, true);
+            assert!(is_open(&circuit), "{rate}");
+        }
+    }
+
+    #[test]
+    fn thresholds_are_at_least_one() {
+        let config = config(
+            CircuitBreakerLayer::new()
+                .with_consecutive_failures(0)
+                .with_failure_rate(1.0, 0),
+        );
+        let mut circuit = Circuit::default();
+
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, false);
+        assert!(!is_open(&circuit));
+        assert_eq!(circuit.acquire(&config), Ok(false));
+        circuit.record(&config, false, true);
+        assert!(is_open(&circuit));
+    }
+}
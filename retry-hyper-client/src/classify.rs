@@ -0,0 +1,192 @@
+use std::{error::Error as StdError, time::Duration};
+
+use hyper::{Request, Response};
+
+use crate::error_kind::ErrorKind;
+
+/// Status codes retried by the [`DefaultClassifier`]
+///
+/// `http` has no constant for 425 Too Early, hence the raw codes.
+const RETRYABLE_STATUSES: [u16; 7] = [408, 425, 429, 500, 502, 503, 504];
+
+/// Decision on whether an attempt should be retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Classification {
+    /// Retry after the usual backoff delay
+    Retry,
+    /// Return the result as is
+    DontRetry,
+    /// Retry, waiting at least for the given duration
+    #[allow(dead_code)]
+    RetryAfter(Duration),
+}
+
+/// Decide whether the result of an attempt should be retried
+///
+/// This is implemented for any `Fn(&Req, Result<&Res, &E>) -> Classification`, so a closure can
+/// be used instead of a dedicated type.
+pub trait Classify<Req, Res, E> {
+    fn classify(&self, req: &Req, result: Result<&Res, &E>) -> Classification;
+}
+
+impl<F, Req, Res, E> Classify<Req, Res, E> for F
+where
+    F: Fn(&Req, Result<&Res, &E>) -> Classification,
+{
+    fn classify(&self, req: &Req, result: Result<&Res, &E>) -> Classification {
+        self(req, result)
+    }
+}
+
+/// Retry on transient status codes and connection errors
+///
+/// The retried status codes are 408, 425, 429, 500, 502, 503 and 504. Errors are retried based on
+/// their [`ErrorKind`], see [`ErrorKind::is_retryable`] for the defaults.
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultClassifier {
+    /// Bit set of the retryable error kinds
+    retryable_errors: u16,
+}
+
+impl DefaultClassifier {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retry errors of this kind
+    #[allow(dead_code)]
+    pub fn with_retryable_error(self, kind: ErrorKind) -> Self {
+        Self {
+            retryable_errors: self.retryable_errors | bit(kind),
+        }
+    }
+
+    /// Never retry errors of this kind
+    #[allow(dead_code)]
+    pub fn with_fatal_error(self, kind: ErrorKind) -> Self {
+        Self {
+            retryable_errors: self.retryable_errors & !bit(kind),
+        }
+    }
+
+    fn is_retryable(&self, kind: ErrorKind) -> bool {
+        self.retryable_errors & bit(kind) != 0
+    }
+}
+
+impl Default for DefaultClassifier {
+    fn default() -> Self {
+        let retryable_errors = ALL_ERROR_KINDS
+            .into_iter()
+            .filter(|kind| kind.is_retryable())
+            .fold(0, |bits, kind| bits | bit(kind));
+        Self { retryable_errors }
+    }
+}
+
+impl<T, B, E> Classify<Request<T>, Response<B>, E> for DefaultClassifier
+where
+    E: StdError + 'static,
+{
+    fn classify(&self, _req: &Request<T>, result: Result<&Response<B>, &E>) -> Classification {
+        match result {
+            Ok(response) if RETRYABLE_STATUSES.contains(&response.status().as_u16()) => {
+                Classification::Retry
+            }
+            Ok(_) => Classification::DontRetry,
+            Err(err) if self.is_retryable(ErrorKind::of(err)) => Classification::Retry,
+            Err(_) => Classification::DontRetry,
+        }
+    }
+}
+
+const ALL_ERROR_KINDS: [ErrorKind; 13] = [
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::Connect,
+    ErrorKind::Timeout,
+    ErrorKind::IncompleteMessage,
+    ErrorKind::Canceled,
+    ErrorKind::Certificate,
+    ErrorKind::Tls,
+    ErrorKind::BodyWrite,
+    ErrorKind::InvalidRequest,
+    ErrorKind::CircuitOpen,
+    ErrorKind::Io,
+    ErrorKind::Other,
+];
+
+fn bit(kind: ErrorKind) -> u16 {
+    1 << kind as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_retryable_errors() {
+        let classifier = DefaultClassifier::new();
+        for kind in ALL_ERROR_KINDS {
+            assert_eq!(
+                classifier.is_retryable(kind),
+                kind.is_retryable(),
+                "{kind:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn toggle_error_kinds() {
+        let default = DefaultClassifier::new();
+        let classifier = default
+            .with_fatal_error(ErrorKind::ConnectionRefused)
+            .with_retryable_error(ErrorKind::Io);
+        for kind in ALL_ERROR_KINDS {
+            let expected = match kind {
+                ErrorKind::ConnectionRefused => false,
+                ErrorKind::Io => true,
+                _ => default.is_retryable(kind),
+            };
+            assert_eq!(classifier.is_retryable(kind), expected, "{kind:?}");
+        }
+
+        // Setting a bit twice, or clearing a cleared one, changes nothing
+        let again = classifier
+            .with_fatal_error(ErrorKind::ConnectionRefused)
+            .with_fatal_error(ErrorKind::Other)
+            .with_retryable_error(ErrorKind::Io);
+        assert_eq!(again.retryable_errors, classifier.retryable_errors);
+
+        let back = classifier
+            .with_retryable_error(ErrorKind::ConnectionRefused)
+            .with_fatal_error(ErrorKind::Io);
+        assert_eq!(back.retryable_errors, default.retryable_errors);
+    }
+
+    #[test]
+    fn all_error_kinds_fit() {
+        let bits = ALL_ERROR_KINDS
+            .into_iter()
+            .fold(0, |bits, kind| bits | bit(kind));
+        assert_eq!(bits.count_ones() as usize, ALL_ERROR_KINDS.len());
+    }
+
+    #[test]
+    fn statuses() {
+        let classifier = DefaultClassifier::new();
+        let req = Request::new(());
+        for status in [200, 404, 501, 503] {
+            let response = Response::builder().status(status).body(()).unwrap();
+            let classification =
+                Classify::<_, _, std::io::Error>::classify(&classifier, &req, Ok(&response));
+            let expected = if RETRYABLE_STATUSES.contains(&status) {
+                Classification::Retry
+            } else {
+                Classification::DontRetry
+            };
+            assert_eq!(classification, expected, "{status}");
+        }
+    }
+}
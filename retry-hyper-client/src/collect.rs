@@ -0,0 +1,331 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use hyper::{
+    body::{Buf, Bytes, HttpBody},
+    http::response::Parts,
+    Request, Response,
+};
+use pin_project_lite::pin_project;
+use tower::{Layer, Service};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Read the whole body of responses as part of each attempt
+///
+/// Below the [`RetryLayer`](crate::retry::RetryLayer), this makes failures while reading the body,
+/// such as a connection closed halfway through, go through the retry policy like any other error.
+/// They are returned as [`CollectError::Body`], whose source is the error of the body, so they are
+/// classified the same way.
+///
+/// Bodies are kept in memory, up to `limit` bytes. Larger ones fail with
+/// [`CollectError::TooLarge`], which is not retried by default. Trailers are dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct CollectLayer {
+    limit: usize,
+}
+
+impl CollectLayer {
+    pub fn new(limit: usize) -> Self {
+        Self { limit }
+    }
+}
+
+impl<S> Layer<S> for CollectLayer {
+    type Service = Collect<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Collect {
+            limit: self.limit,
+            service,
+        }
+    }
+}
+
+/// Service created by [`CollectLayer`]
+#[derive(Clone, Debug)]
+pub struct Collect<S> {
+    limit: usize,
+    service: S,
+}
+
+impl<S, T, B> Service<Request<T>> for Collect<S>
+where
+    S: Service<Request<T>, Response = Response<B>>,
+    B: HttpBody,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<Bytes>;
+    type Error = CollectError<S::Error>;
+    type Future = ResponseFuture<S::Future, B>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(CollectError::Inner)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        ResponseFuture::Called {
+            future: self.service.call(req),
+            limit: self.limit,
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`Collect`]
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F, B> {
+        // Waiting for the response headers
+        Called {
+            #[pin]
+            future: F,
+            limit: usize,
+        },
+        // Reading the body
+        Collecting {
+            parts: Option<Parts>,
+            #[pin]
+            body: B,
+            data: Vec<u8>,
+            limit: usize,
+        },
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: HttpBody,
+    B::Error: Into<BoxError>,
+{
+    type Output = Result<Response<Bytes>, CollectError<E>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.as_mut().project() {
+                ResponseFutureProj::Called { future, limit } => {
+                    let response = ready!(future.poll(cx)).map_err(CollectError::Inner)?;
+                    let limit = *limit;
+                    let (parts, body) = response.into_parts();
+                    if body.size_hint().lower() > limit as u64 {
+                        return Poll::Ready(Err(CollectError::TooLarge(limit)));
+                    }
+                    self.set(ResponseFuture::Collecting {
+                        parts: Some(parts),
+                        body,
+                        data: Vec::new(),
+                        limit,
+                    });
+                }
+                ResponseFutureProj::Collecting {
+                    parts,
+                    mut body,
+                    data,
+                    limit,
+                } => {
+                    while let Some(chunk) = ready!(body.as_mut().poll_data(cx)) {
+                        let mut chunk = chunk.map_err(|err| CollectError::Body(err.into()))?;
+                        if data.len() + chunk.remaining() > *limit {
+                            return Poll::Ready(Err(CollectError::TooLarge(*limit)));
+                        }
+                        while chunk.has_remaining() {
+                            let bytes = chunk.chunk();
+                            let len = bytes.len();
+                            data.extend_from_slice(bytes);
+                            chunk.advance(len);
+                        }
+                    }
+                    let parts = parts.take().expect("polled after completion");
+                    let data = Bytes::from(mem::take(data));
+                    return Poll::Ready(Ok(Response::from_parts(parts, data)));
+                }
+            }
+        }
+    }
+}
+
+/// Error returned by [`Collect`]
+#[derive(Debug)]
+pub enum CollectError<E> {
+    /// The inner service failed
+    Inner(E),
+    /// Reading the body failed
+    Body(BoxError),
+    /// The body is larger than this limit
+    TooLarge(usize),
+}
+
+impl<E> fmt::Display for CollectError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inner(_) => f.write_str("request failed"),
+            Self::Body(_) => f.write_str("failed to read the response body"),
+            Self::TooLarge(limit) => write!(f, "response body larger than {limit} bytes"),
+        }
+    }
+}
+
+impl<E> StdError for CollectError<E>
+where
+    E: StdError + 'static,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Inner(err) => Some(err),
+            Self::Body(err) => Some(&**err),
+            Self::TooLarge(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use hyper::{Body, HeaderMap};
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::{
+        backoff::Backoff, classify::DefaultClassifier, error_kind::ErrorKind, mock::Mock,
+        retry::RetryLayer, sleep::ManualClock,
+    };
+
+    /// Body made of the given chunks, failing where they're errors
+    struct Chunks(VecDeque<Result<Bytes, io::Error>>);
+
+    impl Chunks {
+        fn new(chunks: impl IntoIterator<Item = Result<&'static str, io::ErrorKind>>) -> Self {
+            let chunks = chunks.into_iter().map(|chunk| match chunk {
+                Ok(chunk) => Ok(Bytes::from(chunk)),
+                Err(kind) => Err(io::Error::from(kind)),
+            });
+            Self(chunks.collect())
+        }
+    }
+
+    impl HttpBody for Chunks {
+        type Data = Bytes;
+        type Error = io::Error;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.0.pop_front())
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    fn get() -> Request<()> {
+        Request::get("http://localhost/").body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn collects_response() {
+        let response = Response::builder()
+            .status(201)
+            .header("x-id", "7")
+            .body(Body::from("hello world"))
+            .unwrap();
+        let mock = Mock::new().reply_after(Duration::ZERO, Ok(response));
+
+        let response: Response<Bytes> = CollectLayer::new(64)
+            .layer(mock)
+            .oneshot(get())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["x-id"], "7");
+        assert_eq!(response.into_body(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn too_large_from_size_hint() {
+        let mock = Mock::new().reply_after(Duration::ZERO, Ok(Response::new(Body::from("hello"))));
+        let err = CollectLayer::new(4)
+            .layer(mock)
+            .oneshot(get())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CollectError::TooLarge(4)), "{err:?}");
+        assert_eq!(err.to_string(), "response body larger than 4 bytes");
+        assert!(err.source().is_none());
+    }
+
+    #[tokio::test]
+    async fn too_large_from_stream() {
+        let service = service_fn(|_: Request<()>| async {
+            // No size hint, so the limit is only hit while reading
+            let body = Chunks::new([Ok("abc"), Ok("def")]);
+            Ok::<_, io::Error>(Response::new(body))
+        });
+        let layer = CollectLayer::new(6);
+        let response = layer.layer(service).oneshot(get()).await.unwrap();
+        assert_eq!(response.into_body(), "abcdef");
+
+        let err = CollectLayer::new(5)
+            .layer(service)
+            .oneshot(get())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CollectError::TooLarge(5)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn body_error_is_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = service_fn(|_: Request<()>| {
+            let calls = calls.clone();
+            async move {
+                let body = match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Chunks::new([Ok("par"), Err(io::ErrorKind::ConnectionReset)]),
+                    _ => Chunks::new([Ok("part"), Ok("ial")]),
+                };
+                Ok::<_, io::Error>(Response::new(body))
+            }
+        });
+        let policy = Backoff::<DefaultClassifier, _>::new().with_sleeper(ManualClock::new());
+
+        let response = RetryLayer::new(policy)
+            .layer(CollectLayer::new(64).layer(service))
+            .oneshot(get())
+            .await
+            .unwrap();
+        assert_eq!(response.into_body(), "partial");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The body error is the source, so it's classified like a connection error
+        let service = service_fn(|_: Request<()>| async {
+            let body = Chunks::new([Err(io::ErrorKind::ConnectionReset)]);
+            Ok::<_, io::Error>(Response::new(body))
+        });
+        let err = CollectLayer::new(64)
+            .layer(service)
+            .oneshot(get())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CollectError::Body(_)), "{err:?}");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ConnectionReset);
+    }
+}
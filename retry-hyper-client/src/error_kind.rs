@@ -0,0 +1,254 @@
+use std::{error::Error as StdError, io};
+
+use crate::{circuit::Open, timeout::Elapsed};
+
+/// Category of a transport error, used to decide whether it can be retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The server refused the connection
+    ConnectionRefused,
+    /// The connection was reset, aborted or closed while writing
+    ConnectionReset,
+    /// Any other failure to connect, such as a DNS resolution error
+    Connect,
+    /// An operation timed out
+    Timeout,
+    /// The connection was closed before the response was complete
+    IncompleteMessage,
+    /// The request was canceled before it could be sent, or an HTTP/2 server refused its stream
+    Canceled,
+    /// The server certificate could not be validated
+    Certificate,
+    /// Any other TLS error
+    Tls,
+    /// The request body could not be written
+    BodyWrite,
+    /// The request could not be sent as is, for example because of an invalid URI
+    InvalidRequest,
+    /// The request was rejected by an open circuit breaker
+    CircuitOpen,
+    /// Any other I/O error
+    Io,
+    /// Anything else
+    Other,
+}
+
+impl ErrorKind {
+    /// Categorize an error by walking its source chain
+    ///
+    /// Causes found deeper in the chain, such as I/O or TLS errors, take precedence over the
+    /// generic flags of a `hyper::Error`.
+    pub fn of(err: &(dyn StdError + 'static)) -> Self {
+        let mut fallback = None;
+        let mut source = Some(err);
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<rustls::Error>() {
+                return Self::of_tls(err);
+            }
+            if err.is::<Elapsed>() {
+                return Self::Timeout;
+            }
+            if err.is::<Open>() {
+                return Self::CircuitOpen;
+            }
+            if let Some(err) = err.downcast_ref::<h2::Error>() {
+                if err.reason() == Some(h2::Reason::REFUSED_STREAM) {
+                    return Self::Canceled;
+                }
+            }
+            if let Some(err) = err.downcast_ref::<hyper::Error>() {
+                fallback = fallback.or_else(|| Self::of_hyper(err));
+            }
+
+            source = match err.downcast_ref::<io::Error>() {
+                Some(err) => {
+                    if let Some(kind) = Self::of_io(err.kind()) {
+                        return kind;
+                    }
+                    fallback = fallback.or(Some(Self::Io));
+                    // `io::Error::source` skips the wrapped error, which is where TLS errors are
+                    err.get_ref().map(|err| err as &(dyn StdError + 'static))
+                }
+                None => err.source(),
+            };
+        }
+        fallback.unwrap_or(Self::Other)
+    }
+
+    fn of_hyper(err: &hyper::Error) -> Option<Self> {
+        if err.is_timeout() {
+            Some(Self::Timeout)
+        } else if err.is_connect() {
+            Some(Self::Connect)
+        } else if err.is_incomplete_message() {
+            Some(Self::IncompleteMessage)
+        } else if err.is_canceled() {
+            Some(Self::Canceled)
+        } else if err.is_body_write_aborted() {
+            Some(Self::BodyWrite)
+        } else if err.is_user() {
+            // The only user errors with a cause are the ones coming from the body stream
+            if err.source().is_some() {
+                Some(Self::BodyWrite)
+            } else {
+                Some(Self::InvalidRequest)
+            }
+        } else {
+            None
+        }
+    }
+
+    fn of_io(kind: io::ErrorKind) -> Option<Self> {
+        match kind {
+            io::ErrorKind::ConnectionRefused => Some(Self::ConnectionRefused),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Some(Self::ConnectionReset),
+            io::ErrorKind::TimedOut => Some(Self::Timeout),
+            _ => None,
+        }
+    }
+
+    fn of_tls(err: &rustls::Error) -> Self {
+        match err {
+            rustls::Error::NoCertificatesPresented
+            | rustls::Error::UnsupportedNameType
+            | rustls::Error::InvalidCertificateEncoding
+            | rustls::Error::InvalidCertificateSignatureType
+            | rustls::Error::InvalidCertificateSignature
+            | rustls::Error::InvalidCertificateData(_)
+            | rustls::Error::InvalidSct(_) => Self::Certificate,
+            _ => Self::Tls,
+        }
+    }
+
+    /// Whether this kind of error is retried by default
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::ConnectionRefused
+                | Self::ConnectionReset
+                | Self::Connect
+                | Self::Timeout
+                | Self::IncompleteMessage
+                | Self::Canceled
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use hyper::Client;
+
+    use super::*;
+
+    /// Error that only adds a layer to the source chain
+    #[derive(Debug)]
+    struct Wrap(Box<dyn StdError + Send + Sync>);
+
+    impl fmt::Display for Wrap {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("wrapped")
+        }
+    }
+
+    impl StdError for Wrap {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&*self.0)
+        }
+    }
+
+    fn wrap(err: impl StdError + Send + Sync + 'static) -> Wrap {
+        Wrap(Box::new(err))
+    }
+
+    fn io(kind: io::ErrorKind) -> io::Error {
+        io::Error::from(kind)
+    }
+
+    fn io_wrapping(err: impl StdError + Send + Sync + 'static) -> io::Error {
+        io::Error::other(err)
+    }
+
+    #[test]
+    fn source_chains() {
+        let cases: Vec<(Box<dyn StdError>, ErrorKind)> = vec![
+            (
+                Box::new(io(io::ErrorKind::ConnectionRefused)),
+                ErrorKind::ConnectionRefused,
+            ),
+            (
+                Box::new(wrap(io(io::ErrorKind::ConnectionRefused))),
+                ErrorKind::ConnectionRefused,
+            ),
+            (
+                Box::new(wrap(wrap(io(io::ErrorKind::ConnectionRefused)))),
+                ErrorKind::ConnectionRefused,
+            ),
+            (
+                Box::new(wrap(io(io::ErrorKind::ConnectionReset))),
+                ErrorKind::ConnectionReset,
+            ),
+            (
+                Box::new(wrap(io(io::ErrorKind::ConnectionAborted))),
+                ErrorKind::ConnectionReset,
+            ),
+            (
+                Box::new(wrap(io(io::ErrorKind::BrokenPipe))),
+                ErrorKind::ConnectionReset,
+            ),
+            (
+                Box::new(wrap(io(io::ErrorKind::TimedOut))),
+                ErrorKind::Timeout,
+            ),
+            (Box::new(wrap(io(io::ErrorKind::NotFound))), ErrorKind::Io),
+            (
+                Box::new(io_wrapping(rustls::Error::InvalidCertificateEncoding)),
+                ErrorKind::Certificate,
+            ),
+            (
+                Box::new(wrap(io_wrapping(rustls::Error::NoCertificatesPresented))),
+                ErrorKind::Certificate,
+            ),
+            (
+                Box::new(io_wrapping(rustls::Error::HandshakeNotComplete)),
+                ErrorKind::Tls,
+            ),
+            // The inner I/O error is more precise than the outer one
+            (
+                Box::new(io_wrapping(io(io::ErrorKind::ConnectionReset))),
+                ErrorKind::ConnectionReset,
+            ),
+            (
+                Box::new(io_wrapping(wrap(io(io::ErrorKind::Other)))),
+                ErrorKind::Io,
+            ),
+            (
+                Box::new(wrap(h2::Error::from(h2::Reason::REFUSED_STREAM))),
+                ErrorKind::Canceled,
+            ),
+            (
+                Box::new(wrap(h2::Error::from(h2::Reason::CANCEL))),
+                ErrorKind::Other,
+            ),
+            (Box::new(wrap(wrap(fmt::Error))), ErrorKind::Other),
+        ];
+        for (err, kind) in cases {
+            assert_eq!(ErrorKind::of(&*err), kind, "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn io_cause_of_hyper_error() {
+        // Nothing listens on port 1
+        let err = Client::new()
+            .get("http://127.0.0.1:1".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.is_connect(), "{err:?}");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ConnectionRefused);
+        assert_eq!(ErrorKind::of(&wrap(err)), ErrorKind::ConnectionRefused);
+    }
+}
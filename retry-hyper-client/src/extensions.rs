@@ -0,0 +1,60 @@
+use std::fmt;
+
+use hyper::http::Extensions;
+
+/// Request extensions carried over to each attempt by [`Backoff`](crate::backoff::Backoff)
+///
+/// Extensions can't be cloned as a whole, so only the types registered here are copied. The
+/// `:protocol` pseudo-header of HTTP/2 extended CONNECT requests (`hyper::ext::Protocol`) is
+/// registered by default.
+#[derive(Clone)]
+pub struct ExtensionRegistry {
+    copies: Vec<fn(&Extensions, &mut Extensions)>,
+}
+
+impl ExtensionRegistry {
+    /// Registry without any extension, not even the HTTP/2 pseudo-headers
+    #[allow(dead_code)]
+    pub fn empty() -> Self {
+        Self { copies: Vec::new() }
+    }
+
+    /// Also copy extensions of type `X`
+    pub fn with<X>(mut self) -> Self
+    where
+        X: Clone + Send + Sync + 'static,
+    {
+        self.copies.push(copy::<X>);
+        self
+    }
+
+    /// Copy the registered extensions of `from` into `to`
+    pub fn copy(&self, from: &Extensions, to: &mut Extensions) {
+        for copy in &self.copies {
+            copy(from, to);
+        }
+    }
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self::empty().with::<hyper::ext::Protocol>()
+    }
+}
+
+impl fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionRegistry")
+            .field("types", &self.copies.len())
+            .finish()
+    }
+}
+
+fn copy<X>(from: &Extensions, to: &mut Extensions)
+where
+    X: Clone + Send + Sync + 'static,
+{
+    if let Some(extension) = from.get::<X>() {
+        to.insert(extension.clone());
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use hyper::http::uri::{Authority, Uri};
+
+/// Replicas of a service, which [`Backoff`](crate::backoff::Backoff) fails over between
+///
+/// The first attempt of a request goes to the primary endpoint, and each retry goes to another
+/// one, as chosen by the [`Failover`] strategy. Clones share the health of the endpoints.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    authorities: Vec<Authority>,
+    failover: Failover,
+    cooldown: Duration,
+    /// Until when each endpoint is considered unhealthy
+    unhealthy_until: Arc<Mutex<Vec<Option<Instant>>>>,
+}
+
+/// How [`Endpoints`] chooses the endpoint for a retry
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Failover {
+    /// The endpoint after the previous one, in order
+    #[default]
+    RoundRobin,
+    /// The first healthy endpoint after the previous one, in order
+    ///
+    /// This falls back to `RoundRobin` when no endpoint is healthy.
+    SkipUnhealthy,
+}
+
+#[allow(dead_code)]
+impl Endpoints {
+    pub fn new(primary: Authority) -> Self {
+        Self {
+            authorities: vec![primary],
+            failover: Failover::default(),
+            cooldown: Duration::from_secs(30),
+            unhealthy_until: Arc::new(Mutex::new(vec![None])),
+        }
+    }
+
+    /// Add an endpoint to fail over to, after the ones already added
+    ///
+    /// The result no longer shares the health of the endpoints with earlier clones.
+    pub fn with_endpoint(mut self, authority: Authority) -> Self {
+        let mut unhealthy_until = self.lock().clone();
+        unhealthy_until.push(None);
+        self.authorities.push(authority);
+        Self {
+            unhealthy_until: Arc::new(Mutex::new(unhealthy_until)),
+            ..self
+        }
+    }
+
+    pub fn with_failover(self, failover: Failover) -> Self {
+        Self { failover, ..self }
+    }
+
+    /// How long an endpoint is skipped after a failed attempt, with [`Failover::SkipUnhealthy`]
+    pub fn with_cooldown(self, cooldown: Duration) -> Self {
+        Self { cooldown, ..self }
+    }
+
+    /// Number of endpoints, including the primary one
+    pub(crate) fn len(&self) -> usize {
+        self.authorities.len()
+    }
+
+    /// Record the outcome of an attempt sent to the endpoint at `index`
+    pub(crate) fn record(&self, index: usize, healthy: bool, now: Instant) {
+        let mut unhealthy_until = self.lock();
+        if let Some(until) = unhealthy_until.get_mut(index) {
+            *until = if healthy {
+                None
+            } else {
+                Some(now + self.cooldown)
+            };
+        }
+    }
+
+    /// Index of the endpoint to retry on, after an attempt sent to the endpoint at `index`
+    pub(crate) fn next(&self, index: usize, now: Instant) -> usize {
+        let len = self.authorities.len();
+        let round_robin = (index + 1) % len;
+        match self.failover {
+            Failover::RoundRobin => round_robin,
+            Failover::SkipUnhealthy => {
+                let unhealthy_until = self.lock();
+                (1..=len)
+                    .map(|offset| (index + offset) % len)
+                    .find(|&next| match unhealthy_until.get(next) {
+                        Some(Some(until)) => *until <= now,
+                        _ => true,
+                    })
+                    .unwrap_or(round_robin)
+            }
+        }
+    }
+
+    /// `uri` with its authority replaced by the one of the endpoint at `index`
+    ///
+    /// This is `None` if `uri` has no scheme, as it can't have an authority then.
+    pub(crate) fn uri(&self, uri: &Uri, index: usize) -> Option<Uri> {
+        let mut parts = uri.clone().into_parts();
+        parts.authority = Some(self.authorities[index].clone());
+        Uri::from_parts(parts).ok()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Option<Instant>>> {
+        self.unhealthy_until
+            .lock()
+            .expect("endpoints lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header::HOST, Request, Response};
+    use tower::{retry::Policy, Layer, ServiceExt};
+
+    use super::*;
+    use crate::{
+        backoff::Backoff,
+        circuit::CircuitBreakerLayer,
+        classify::DefaultClassifier,
+        mock::{Mock, MockError},
+        retry::{RetryError, RetryLayer},
+        sleep::{Clock, ManualClock},
+    };
+
+    fn endpoints() -> Endpoints {
+        Endpoints::new(Authority::from_static("a.test"))
+            .with_endpoint(Authority::from_static("b.test"))
+            .with_endpoint(Authority::from_static("c.test"))
+    }
+
+    fn backoff(
+        endpoints: &Endpoints,
+        clock: &ManualClock,
+    ) -> Backoff<DefaultClassifier, ManualClock> {
+        Backoff::new()
+            .with_sleeper(clock.clone())
+            .with_endpoints(endpoints.clone())
+    }
+
+    fn get(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    /// URIs of the requests received by `mock`
+    fn uris(mock: &Mock) -> Vec<String> {
+        mock.requests()
+            .iter()
+            .map(|seen| seen.uri.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rotates_across_endpoints() {
+        let clock = ManualClock::new();
+        let layer = RetryLayer::new(backoff(&endpoints(), &clock).with_attempts(3));
+        let mock = Mock::new().status(503).status(503).status(503);
+
+        let response = layer
+            .layer(mock.clone())
+            .oneshot(get("http://a.test/items?page=2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            uris(&mock),
+            [
+                "http://a.test/items?page=2",
+                "http://b.test/items?page=2",
+                "http://c.test/items?page=2",
+                "http://a.test/items?page=2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_host_header() {
+        let clock = ManualClock::new();
+        let layer = RetryLayer::new(backoff(&endpoints(), &clock));
+        let mock = Mock::new().status(503);
+        let mut req = get("http://a.test/");
+        req.headers_mut().insert(HOST, "a.test".parse().unwrap());
+
+        layer.layer(mock.clone()).oneshot(req).await.unwrap();
+        let seen = mock.requests();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.iter().all(|seen| !seen.headers.contains_key(HOST)));
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_endpoints() {
+        let clock = ManualClock::new();
+        let endpoints = endpoints().with_failover(Failover::SkipUnhealthy);
+        let layer = RetryLayer::new(backoff(&endpoints, &clock));
+        let mock = Mock::new().status(503).status(503);
+
+        layer
+            .layer(mock.clone())
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap();
+        assert_eq!(
+            uris(&mock),
+            ["http://a.test/", "http://b.test/", "http://c.test/"]
+        );
+
+        // `b` is still cooling down, and `c` just succeeded
+        let mock = mock.status(503);
+        layer
+            .layer(mock.clone())
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap();
+        assert_eq!(uris(&mock), ["http://a.test/", "http://c.test/"]);
+
+        // `b` is back once its cooldown is over
+        clock.advance(Duration::from_secs(31));
+        let mock = Mock::new().status(503);
+        layer
+            .layer(mock.clone())
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap();
+        assert_eq!(uris(&mock), ["http://a.test/", "http://b.test/"]);
+    }
+
+    #[tokio::test]
+    async fn errors_mark_endpoint_unhealthy() {
+        let clock = ManualClock::new();
+        let endpoints = endpoints().with_failover(Failover::SkipUnhealthy);
+        let layer = RetryLayer::new(backoff(&endpoints, &clock));
+
+        // Not retried by the default classifier, but not healthy either
+        let mock = Mock::new().error("unknown failure");
+        let err = layer
+            .layer(mock)
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RetryError::NonRetryable(_)), "{err:?}");
+        assert_eq!(endpoints.next(2, clock.now()), 1);
+
+        let mock = Mock::new().status(404);
+        layer
+            .layer(mock)
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap();
+        assert_eq!(endpoints.next(2, clock.now()), 0);
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_open_circuit() {
+        let clock = ManualClock::new();
+        let breaker = CircuitBreakerLayer::new().with_consecutive_failures(1);
+        let mock = Mock::new().status(503);
+
+        // Open the circuit of `a`
+        let response = breaker
+            .layer(mock.clone())
+            .oneshot(get("http://a.test/"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        mock.requests();
+
+        // Even a POST without an idempotency key is failed over, as `a` never saw it
+        let retry =
+            RetryLayer::new(backoff(&endpoints(), &clock)).layer(breaker.layer(mock.clone()));
+        let req = Request::post("http://a.test/").body(()).unwrap();
+        let response = retry.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(uris(&mock), ["http://b.test/"]);
+    }
+
+    #[tokio::test]
+    async fn relative_uri_is_not_retried() {
+        let clock = ManualClock::new();
+        let policy = backoff(&endpoints(), &clock);
+        let req = get("/items");
+        assert!(Policy::<_, Response<()>, MockError>::clone_request(&policy, &req).is_none());
+
+        let mock = Mock::new().status(503);
+        let response = RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(uris(&mock), ["/items"]);
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use hyper::Request;
+use pin_project_lite::pin_project;
+use tokio::time::{sleep, Instant, Sleep};
+use tower::{retry::Policy, Layer, Service};
+
+use crate::{
+    classify::{Classification, Classify, DefaultClassifier},
+    idempotency::is_idempotent,
+};
+
+/// Number of latencies kept to compute percentiles
+const LATENCY_SAMPLES: usize = 1000;
+
+/// Send duplicate requests when the first one is slow, and keep the first response
+///
+/// This is an alternative to sequential retries for latency-sensitive requests. If no response
+/// headers came back after the hedging delay, another copy of the request is sent, built with the
+/// [`Policy::clone_request`] of `policy`. The first response that the classifier `C` doesn't ask
+/// to retry wins and the other requests are dropped. Other responses are only returned once all
+/// the requests sent have completed, like errors. Only requests with an idempotent method are
+/// hedged.
+///
+/// The delay is either fixed, or a percentile of the latencies observed so far. The latency of
+/// each response is measured from when its own attempt was sent.
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct HedgeLayer<P, C = DefaultClassifier> {
+    policy: P,
+    classifier: C,
+    config: Config,
+    latencies: Arc<Mutex<VecDeque<Duration>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    /// Delay before sending a hedge, used until there are enough samples for `percentile`
+    delay: Duration,
+    /// Percentile of observed latencies to use as a delay, and how many samples it needs
+    percentile: Option<(f64, usize)>,
+    /// Maximum number of hedges per request, on top of the first attempt
+    max_hedges: usize,
+}
+
+#[allow(dead_code)]
+impl<P> HedgeLayer<P> {
+    /// Hedge after a fixed `delay`
+    pub fn new(policy: P, delay: Duration) -> Self {
+        Self {
+            policy,
+            classifier: DefaultClassifier::default(),
+            config: Config {
+                delay,
+                percentile: None,
+                max_hedges: 1,
+            },
+            latencies: Default::default(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<P, C> HedgeLayer<P, C> {
+    /// Decide which responses win with `classifier`, instead of the [`DefaultClassifier`]
+    pub fn with_classifier<D>(self, classifier: D) -> HedgeLayer<P, D> {
+        HedgeLayer {
+            policy: self.policy,
+            classifier,
+            config: self.config,
+            latencies: self.latencies,
+        }
+    }
+
+    /// Hedge after the given percentile (between 0.0 and 1.0) of the observed latencies
+    ///
+    /// The fixed delay is used until `min_samples` latencies have been observed.
+    pub fn with_percentile(mut self, percentile: f64, min_samples: usize) -> Self {
+        self.config.percentile = Some((percentile.clamp(0.0, 1.0), min_samples.max(1)));
+        self
+    }
+
+    pub fn with_max_hedges(mut self, max_hedges: usize) -> Self {
+        self.config.max_hedges = max_hedges;
+        self
+    }
+}
+
+impl<P, C, S> Layer<S> for HedgeLayer<P, C>
+where
+    P: Clone,
+    C: Clone,
+{
+    type Service = Hedge<P, S, C>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Hedge {
+            policy: self.policy.clone(),
+            classifier: self.classifier.clone(),
+            config: self.config,
+            latencies: self.latencies.clone(),
+            service,
+        }
+    }
+}
+
+/// Service created by [`HedgeLayer`]
+#[derive(Clone)]
+pub struct Hedge<P, S, C = DefaultClassifier> {
+    policy: P,
+    classifier: C,
+    config: Config,
+    latencies: Arc<Mutex<VecDeque<Duration>>>,
+    service: S,
+}
+
+impl<P, S, C> Hedge<P, S, C> {
+    fn delay(&self) -> Duration {
+        let (percentile, min_samples) = match self.config.percentile {
+            Some(percentile) => percentile,
+            None => return self.config.delay,
+        };
+
+        let mut latencies: Vec<_> = self
+            .latencies
+            .lock()
+            .expect("latencies lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        if latencies.len() < min_samples {
+            return self.config.delay;
+        }
+        latencies.sort_unstable();
+        let index = ((latencies.len() - 1) as f64 * percentile).round() as usize;
+        latencies[index]
+    }
+}
+
+impl<P, S, C, T> Service<Request<T>> for Hedge<P, S, C>
+where
+    P: Policy<Request<T>, S::Response, S::Error> + Clone,
+    S: Service<Request<T>> + Clone,
+    C: Classify<Request<T>, S::Response, S::Error> + Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<P, S, C, Request<T>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        let template = if is_idempotent(req.method()) && self.config.max_hedges > 0 {
+            self.policy.clone_request(&req)
+        } else {
+            None
+        };
+        let delay = self.delay();
+
+        let attempts = FuturesUnordered::new();
+        attempts.push(Timed::new(self.service.call(req)));
+
+        ResponseFuture {
+            sleep: template.as_ref().map(|_| Box::pin(sleep(delay))),
+            template,
+            policy: self.policy.clone(),
+            classifier: self.classifier.clone(),
+            service: self.service.clone(),
+            attempts,
+            hedges: self.config.max_hedges,
+            delay,
+            latencies: self.latencies.clone(),
+            failure: None,
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`Hedge`]
+    pub struct ResponseFuture<P, S, C, Req>
+    where
+        S: Service<Req>,
+    {
+        template: Option<Req>,
+        policy: P,
+        classifier: C,
+        service: S,
+        attempts: FuturesUnordered<Timed<S::Future>>,
+        // Time until the next hedge, if any is left
+        sleep: Option<Pin<Box<Sleep>>>,
+        // Number of hedges left
+        hedges: usize,
+        delay: Duration,
+        latencies: Arc<Mutex<VecDeque<Duration>>>,
+        // Latest failure, returned if all the attempts fail
+        failure: Option<Result<S::Response, S::Error>>,
+    }
+}
+
+impl<P, S, C, Req> Future for ResponseFuture<P, S, C, Req>
+where
+    P: Policy<Req, S::Response, S::Error>,
+    S: Service<Req>,
+    C: Classify<Req, S::Response, S::Error>,
+{
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        loop {
+            let response = match this.attempts.poll_next_unpin(cx) {
+                Poll::Ready(Some((Ok(response), latency))) => {
+                    record(this.latencies, latency);
+                    response
+                }
+                Poll::Ready(Some((Err(err), _))) => {
+                    *this.failure = Some(Err(err));
+                    continue;
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            // Keep waiting for a better response, unless the request isn't hedged
+            let retryable = this.template.as_ref().is_some_and(|template| {
+                this.classifier.classify(template, Ok(&response)) != Classification::DontRetry
+            });
+            if !retryable {
+                return Poll::Ready(Ok(response));
+            }
+            *this.failure = Some(Ok(response));
+        }
+
+        // Everything that was sent failed, don't wait for a hedge
+        if this.attempts.is_empty() {
+            return Poll::Ready(this.failure.take().expect("polled after completion"));
+        }
+
+        let sleep = match this.sleep.as_mut() {
+            Some(sleep) => sleep,
+            None => return Poll::Pending,
+        };
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        match this.service.poll_ready(cx) {
+            Poll::Ready(Ok(())) => (),
+            // Keep waiting for the requests already sent
+            Poll::Ready(Err(err)) => {
+                *this.failure = Some(Err(err));
+                *this.sleep = None;
+                return Poll::Pending;
+            }
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let template = this.template.as_ref().expect("hedging requires a template");
+        if let Some(req) = this.policy.clone_request(template) {
+            this.attempts.push(Timed::new(this.service.call(req)));
+        }
+        *this.hedges -= 1;
+        if *this.hedges == 0 {
+            *this.sleep = None;
+        } else {
+            sleep.as_mut().reset(Instant::now() + *this.delay);
+        }
+
+        // Poll the new attempt, and register the timer for the next hedge
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Keep the latency of a response, measured from when its attempt was sent
+fn record(latencies: &Mutex<VecDeque<Duration>>, latency: Duration) {
+    let mut latencies = latencies.lock().expect("latencies lock poisoned");
+    if latencies.len() >= LATENCY_SAMPLES {
+        latencies.pop_front();
+    }
+    latencies.push_back(latency);
+}
+
+pin_project! {
+    /// Attempt along with when it was sent
+    pub struct Timed<F> {
+        #[pin]
+        future: F,
+        start: Instant,
+    }
+}
+
+impl<F> Timed<F> {
+    fn new(future: F) -> Self {
+        Self {
+            future,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Output = (F::Output, Duration);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = ready!(this.future.poll(cx));
+        Poll::Ready((output, this.start.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Method, Response};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        backoff::Backoff,
+        mock::{Mock, MockError},
+    };
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    async fn send(
+        layer: &HedgeLayer<Backoff>,
+        mock: &Mock,
+        method: Method,
+    ) -> Result<Response<Body>, MockError> {
+        let req = Request::builder().method(method).body(()).unwrap();
+        layer.layer(mock.clone()).oneshot(req).await
+    }
+
+    /// When each request was received, relative to `start`
+    fn received(mock: &Mock, start: Instant) -> Vec<Duration> {
+        mock.requests().iter().map(|seen| seen.at - start).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hedges_after_delay() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY);
+        let mock = Mock::new()
+            .status_after(ms(500), 200)
+            .status_after(ms(50), 204);
+        let start = Instant::now();
+
+        let response = send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(start.elapsed(), ms(150));
+        assert_eq!(received(&mock, start), [ms(0), ms(100)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fast_response_is_not_hedged() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY);
+        let mock = Mock::new().status_after(ms(50), 200);
+        let start = Instant::now();
+
+        let response = send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(received(&mock, start), [ms(0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_non_retryable_response_wins() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY).with_max_hedges(2);
+        let mock = Mock::new()
+            .status_after(ms(500), 200)
+            .status_after(ms(10), 503)
+            .status_after(ms(50), 404);
+        let start = Instant::now();
+
+        let response = send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(start.elapsed(), ms(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retryable_response_waits_for_others() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY);
+        let mock = Mock::new()
+            .status_after(ms(300), 500)
+            .status_after(ms(0), 503);
+        let start = Instant::now();
+
+        // Both are retryable, so the latest one is returned once both are done
+        let response = send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(start.elapsed(), ms(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_hedges() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY).with_max_hedges(2);
+        let mock = Mock::new()
+            .status_after(ms(1000), 200)
+            .status_after(ms(1000), 200)
+            .status_after(ms(1000), 200);
+        let start = Instant::now();
+
+        send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(received(&mock, start), [ms(0), ms(100), ms(200)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn non_idempotent_requests_are_not_hedged() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY);
+        let mock = Mock::new().status_after(ms(1000), 200);
+        let start = Instant::now();
+
+        send(&layer, &mock, Method::POST).await.unwrap();
+        assert_eq!(received(&mock, start), [ms(0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn percentile_delay() {
+        let layer = HedgeLayer::new(Backoff::new(), ms(1000)).with_percentile(0.5, 3);
+        let mock = Mock::new()
+            .status_after(ms(10), 200)
+            .status_after(ms(30), 200)
+            .status_after(ms(20), 200);
+        for _ in 0..3 {
+            send(&layer, &mock, Method::GET).await.unwrap();
+        }
+        mock.requests();
+
+        // The median latency is 20ms
+        let mock = mock.status_after(ms(500), 200).status_after(ms(500), 200);
+        let start = Instant::now();
+        send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(received(&mock, start), [ms(0), ms(20)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_is_measured_per_attempt() {
+        let layer = HedgeLayer::new(Backoff::new(), DELAY).with_percentile(0.0, 1);
+        // The hedge wins 50ms after being sent, 150ms after the first attempt
+        let mock = Mock::new()
+            .status_after(ms(500), 200)
+            .status_after(ms(50), 200);
+        send(&layer, &mock, Method::GET).await.unwrap();
+        mock.requests();
+
+        let mock = mock.status_after(ms(500), 200).status_after(ms(500), 200);
+        let start = Instant::now();
+        send(&layer, &mock, Method::GET).await.unwrap();
+        assert_eq!(received(&mock, start), [ms(0), ms(50)]);
+    }
+}
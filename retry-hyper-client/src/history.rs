@@ -0,0 +1,114 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use hyper::StatusCode;
+
+use crate::error_kind::ErrorKind;
+
+/// Attempts made by the [`RetryLayer`](crate::retry::RetryLayer) for a request, in order
+///
+/// This is added to the extensions of the final response, and is available on the
+/// [`RetryError`](crate::retry::RetryError) when the request fails.
+#[derive(Clone, Debug, Default)]
+pub struct AttemptHistory {
+    attempts: Vec<AttemptRecord>,
+}
+
+#[allow(dead_code)]
+impl AttemptHistory {
+    pub fn attempts(&self) -> &[AttemptRecord] {
+        &self.attempts
+    }
+
+    /// Number of attempts made, including the first one
+    pub fn len(&self) -> usize {
+        self.attempts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attempts.is_empty()
+    }
+
+    /// Record an attempt that started at `start`, and just finished with `outcome`
+    pub(crate) fn push(&mut self, start: Instant, outcome: Outcome) {
+        self.attempts.push(AttemptRecord {
+            start,
+            duration: start.elapsed(),
+            outcome,
+            delay: None,
+        });
+    }
+
+    /// Record that the next attempt is starting, which ends the delay after the last one
+    pub(crate) fn next_attempt(&mut self) {
+        if let Some(last) = self.attempts.last_mut() {
+            last.delay = Some((last.start + last.duration).elapsed());
+        }
+    }
+}
+
+/// One attempt of an [`AttemptHistory`]
+#[derive(Clone, Debug)]
+pub struct AttemptRecord {
+    start: Instant,
+    duration: Duration,
+    outcome: Outcome,
+    delay: Option<Duration>,
+}
+
+#[allow(dead_code)]
+impl AttemptRecord {
+    /// When the attempt was sent
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Time until the response headers or the error
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn outcome(&self) -> &Outcome {
+        &self.outcome
+    }
+
+    /// Time waited after this attempt, before the next one
+    ///
+    /// This is `None` for the last attempt.
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
+}
+
+/// How an attempt ended
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    /// The server responded with this status
+    Status(StatusCode),
+    /// The attempt failed, with the kind and message of the error
+    Error(ErrorKind, String),
+}
+
+impl Outcome {
+    pub(crate) fn of<B, E>(result: Result<&hyper::Response<B>, &E>) -> Self
+    where
+        E: StdError + 'static,
+    {
+        match result {
+            Ok(response) => Self::Status(response.status()),
+            Err(err) => Self::Error(ErrorKind::of(err), err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "{status}"),
+            Self::Error(kind, message) => write!(f, "{kind:?}: {message}"),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{future::Future, pin::Pin};
+
+use hyper::Request;
+
+use crate::history::Outcome;
+
+/// Future returned by [`BeforeRetry::before_retry`]
+pub type Rewrite = Pin<Box<dyn Future<Output = Request<()>> + Send>>;
+
+/// Hook to rewrite a request before it is retried, such as to refresh a token or sign it again
+///
+/// It is given the head of the request as it was last sent, without the attempt header of
+/// [`Backoff`](crate::backoff::Backoff), the outcome of the last attempt and the number of the
+/// next attempt. The URI, method and headers of the head it returns are used for the next
+/// attempts, until it is called again.
+///
+/// This is implemented for async closures taking the same arguments.
+pub trait BeforeRetry: Send + Sync {
+    fn before_retry(&self, req: Request<()>, outcome: Outcome, attempt: usize) -> Rewrite;
+}
+
+impl<F, Fut> BeforeRetry for F
+where
+    F: Fn(Request<()>, Outcome, usize) -> Fut + Send + Sync,
+    Fut: Future<Output = Request<()>> + Send + 'static,
+{
+    fn before_retry(&self, req: Request<()>, outcome: Outcome, attempt: usize) -> Rewrite {
+        Box::pin(self(req, outcome, attempt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use hyper::header::AUTHORIZATION;
+    use tokio::time::{sleep, Instant};
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+    use crate::{backoff::Backoff, mock::Mock, retry::RetryLayer};
+
+    /// Call of the hook, with the time since the first attempt
+    #[derive(Debug)]
+    struct Call {
+        at: Duration,
+        head: Request<()>,
+        outcome: String,
+        attempt: usize,
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn clone_head(head: &Request<()>) -> Request<()> {
+        let mut clone = Request::new(());
+        *clone.uri_mut() = head.uri().clone();
+        *clone.method_mut() = head.method().clone();
+        *clone.headers_mut() = head.headers().clone();
+        clone
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rewrites_after_delay() {
+        let start = Instant::now();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let calls = calls.clone();
+            move |mut head: Request<()>, outcome: Outcome, attempt: usize| {
+                calls.lock().unwrap().push(Call {
+                    at: start.elapsed(),
+                    head: clone_head(&head),
+                    outcome: outcome.to_string(),
+                    attempt,
+                });
+                async move {
+                    // Such as fetching a new token
+                    sleep(ms(10)).await;
+                    if attempt == 2 {
+                        head.headers_mut()
+                            .insert(AUTHORIZATION, "Bearer fresh".parse().unwrap());
+                        *head.uri_mut() = "http://localhost/v2".parse().unwrap();
+                    }
+                    head
+                }
+            }
+        };
+        let policy = Backoff::new().with_attempt_header().with_before_retry(hook);
+        let mock = Mock::new().status(503).status(503);
+        let req = Request::get("http://localhost/v1")
+            .header(AUTHORIZATION, "Bearer stale")
+            .body(())
+            .unwrap();
+
+        let response = RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        // Called once each delay is over, with the head as last sent but without the attempt header
+        let calls = calls.lock().unwrap();
+        let summary: Vec<_> = calls
+            .iter()
+            .map(|call| (call.at, call.attempt, call.outcome.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (ms(100), 2, "503 Service Unavailable"),
+                (ms(310), 3, "503 Service Unavailable"),
+            ]
+        );
+        assert!(calls
+            .iter()
+            .all(|call| !call.head.headers().contains_key("x-retry-attempt")));
+        assert_eq!(calls[0].head.uri(), "http://localhost/v1");
+        assert_eq!(calls[0].head.headers()[AUTHORIZATION], "Bearer stale");
+        assert_eq!(calls[1].head.uri(), "http://localhost/v2");
+        assert_eq!(calls[1].head.headers()[AUTHORIZATION], "Bearer fresh");
+
+        // The rewritten head is sent on the next attempt, and kept for the ones after it
+        let seen = mock.requests();
+        let sent: Vec<_> = seen
+            .iter()
+            .map(|seen| {
+                let auth = seen.headers[AUTHORIZATION].to_str().unwrap();
+                let attempt = seen.headers["x-retry-attempt"].to_str().unwrap();
+                (seen.at - start, seen.uri.path(), auth, attempt)
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (ms(0), "/v1", "Bearer stale", "1"),
+                (ms(110), "/v2", "Bearer fresh", "2"),
+                (ms(320), "/v2", "Bearer fresh", "3"),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn not_called_without_retry() {
+        let calls = Arc::new(Mutex::new(0));
+        let hook = {
+            let calls = calls.clone();
+            move |head: Request<()>, _: Outcome, _: usize| {
+                *calls.lock().unwrap() += 1;
+                async move { head }
+            }
+        };
+        let policy = Backoff::new().with_before_retry(hook);
+        let mock = Mock::new().status(404);
+        let req = Request::get("http://localhost/").body(()).unwrap();
+
+        let response = RetryLayer::new(policy)
+            .layer(mock)
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(*calls.lock().unwrap(), 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::error::Error as StdError;
+
+use hyper::{header::HeaderName, Method};
+
+/// Header used by servers to deduplicate replayed requests
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Whether a request with this method can be sent several times safely
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+/// Whether the request provably never reached the server
+///
+/// This is the case when we could not connect, or when an HTTP/2 server refused the stream
+/// before processing it.
+pub fn is_unsent(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            if err.is_connect() {
+                return true;
+            }
+        }
+        if let Some(err) = err.downcast_ref::<h2::Error>() {
+            return err.reason() == Some(h2::Reason::REFUSED_STREAM);
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Generate a random key, formatted as a version 4 UUID
+#[cfg(feature = "rand")]
+pub fn generate_key() -> String {
+    let bytes: [u8; 16] = rand::random();
+    let value = u128::from_be_bytes(bytes);
+    // Set the version (4) and variant (RFC 4122) bits
+    let value = (value & !(0xf << 76) | (0x4 << 76)) & !(0x3 << 62) | (0x2 << 62);
+    let hex = format!("{value:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Client, Request};
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+    use crate::{
+        backoff::Backoff, classify::DefaultClassifier, mock::Mock, retry::RetryLayer,
+        sleep::ManualClock,
+    };
+
+    fn backoff() -> Backoff<DefaultClassifier, ManualClock> {
+        Backoff::new().with_sleeper(ManualClock::new())
+    }
+
+    fn post() -> Request<()> {
+        Request::post("http://localhost/orders").body(()).unwrap()
+    }
+
+    async fn send(policy: Backoff<DefaultClassifier, ManualClock>, mock: &Mock, req: Request<()>) {
+        // Whether the last result is an error doesn't matter here, only what was sent
+        let _ = RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(req)
+            .await;
+    }
+
+    /// Values of the `Idempotency-Key` header of the requests received by `mock`
+    fn keys(mock: &Mock) -> Vec<Option<String>> {
+        mock.requests()
+            .iter()
+            .map(|seen| {
+                let key = seen.headers.get(IDEMPOTENCY_KEY)?;
+                Some(key.to_str().unwrap().to_owned())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn post_after_response_is_not_retried() {
+        let mock = Mock::new().status(503);
+        send(backoff(), &mock, post()).await;
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn post_after_connect_error_is_retried() {
+        // Nothing listens on port 1
+        let err = Client::new()
+            .get("http://127.0.0.1:1".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.is_connect(), "{err:?}");
+        assert!(is_unsent(&err));
+
+        let mock = Mock::new().error(err);
+        send(backoff(), &mock, post()).await;
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn post_after_refused_stream_is_retried() {
+        let err = h2::Error::from(h2::Reason::REFUSED_STREAM);
+        assert!(is_unsent(&err));
+
+        let mock = Mock::new().error(err);
+        send(backoff(), &mock, post()).await;
+        assert_eq!(mock.requests().len(), 2);
+
+        // Any other reason may come after the server started processing the request
+        let mock = Mock::new().error(h2::Error::from(h2::Reason::INTERNAL_ERROR));
+        send(backoff(), &mock, post()).await;
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn caller_key_allows_retries() {
+        let mock = Mock::new().status(503);
+        let mut req = post();
+        req.headers_mut()
+            .insert(IDEMPOTENCY_KEY, "order-1".parse().unwrap());
+
+        send(backoff(), &mock, req).await;
+        let key = Some("order-1".to_owned());
+        assert_eq!(keys(&mock), [key.clone(), key]);
+    }
+
+    #[cfg(feature = "rand")]
+    #[tokio::test]
+    async fn generated_key_is_sent_on_every_attempt() {
+        let mock = Mock::new().status(503).status(503);
+        send(backoff().with_idempotency_key(), &mock, post()).await;
+
+        let keys = keys(&mock);
+        assert_eq!(keys.len(), 3);
+        assert!(keys[0].is_some());
+        assert!(keys.iter().all(|key| *key == keys[0]));
+    }
+
+    #[cfg(feature = "rand")]
+    #[tokio::test]
+    async fn generated_key_is_not_trusted_by_tower() {
+        // `tower::retry::RetryLayer` sends the caller's request, without the key, first
+        let mock = Mock::new().status(503);
+        let policy = backoff().with_idempotency_key();
+        let response = tower::retry::RetryLayer::new(policy)
+            .layer(mock.clone())
+            .oneshot(post())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(keys(&mock), [None]);
+    }
+
+    #[test]
+    fn unsent_errors() {
+        assert!(!is_unsent(&h2::Error::from(h2::Reason::CANCEL)));
+        assert!(!is_unsent(&std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused
+        )));
+    }
+}
@@ -1,11 +1,40 @@
 use std::time::Duration;
 
-use hyper::{Client, Request};
+use hyper::{Body, Client, Request};
 use hyper_rustls::HttpsConnectorBuilder;
-use tower::{retry::RetryLayer, Layer, Service};
+use tower::{Service, ServiceBuilder};
 
+mod adaptive;
+use adaptive::AdaptiveLayer;
 mod backoff;
 use backoff::Backoff;
+mod body;
+use body::ReplayBody;
+mod circuit;
+use circuit::CircuitBreakerLayer;
+mod classify;
+mod collect;
+use collect::CollectLayer;
+mod error_kind;
+mod extensions;
+mod failover;
+mod hedge;
+mod history;
+mod hook;
+mod idempotency;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod retry;
+use retry::RetryLayer;
+mod retry_after;
+#[cfg(feature = "rand")]
+mod rng;
+mod schedule;
+mod sleep;
+mod timeout;
+use timeout::TimeoutLayer;
 
 #[tokio::main]
 async fn main() {
@@ -17,16 +46,34 @@ async fn main() {
         .enable_http2()
         .build();
 
-    let policy = Backoff::default().with_max_delay(Duration::from_secs(2)).with_jitter(Duration::from_millis(10));
-    let retry_layer = RetryLayer::new(policy);
+    let policy = Backoff::default().with_max_delay(Duration::from_secs(2));
+    #[cfg(feature = "rand")]
+    let policy = policy.with_jitter(Duration::from_millis(10));
 
-    let mut client = retry_layer.layer(Client::builder().build::<_, String>(https_connector));
+    // The rate limiter and the circuit breaker sit inside the retry layer, so that each attempt
+    // goes through them, and retries stop as soon as the circuit opens
+    let mut client = ServiceBuilder::new()
+        .layer(RetryLayer::new(policy))
+        .layer(AdaptiveLayer::new())
+        .layer(CircuitBreakerLayer::new())
+        // Read the body within each attempt, so that failures while reading it are retried too
+        .layer(CollectLayer::new(1024 * 1024))
+        .layer(
+            TimeoutLayer::new()
+                .with_headers(Duration::from_secs(5))
+                .with_multiplier(1.5),
+        )
+        .service(Client::builder().build::<_, ReplayBody>(https_connector));
 
     let request = Request::builder()
         .uri("https://google.com/asdfasdf")
-        .body(Default::default())
+        .body(ReplayBody::new(Body::empty(), 64 * 1024))
         .expect("failed to create request");
 
-    let res = client.call(request).await.expect("failed to call");
+    // The retry future is `Send`, so it can run on any worker thread
+    let res = tokio::spawn(client.call(request))
+        .await
+        .expect("failed to join")
+        .expect("failed to call");
     dbg!(res);
 }
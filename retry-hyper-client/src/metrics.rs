@@ -0,0 +1,70 @@
+use std::{error::Error as StdError, time::Duration};
+
+use hyper::{Request, Response};
+
+use crate::{backoff::Stop, error_kind::ErrorKind};
+
+/// Retries made, by `reason` (status code or error kind)
+const RETRIES: &str = "http_client_retries_total";
+/// Requests that were still failing when retries stopped, by `reason`
+const GIVE_UPS: &str = "http_client_retry_give_ups_total";
+/// Attempts made for each request, including the first one
+const ATTEMPTS: &str = "http_client_attempts";
+/// Time slept before each retry, in seconds
+const SLEEP: &str = "http_client_retry_sleep_seconds";
+
+/// Record the decision [`Backoff`](crate::backoff::Backoff) took after an attempt
+///
+/// All the metrics are labeled with the `authority` and `method` of the request.
+pub fn record<T, B, E>(
+    req: &Request<T>,
+    result: Result<&Response<B>, &E>,
+    attempt: usize,
+    decision: Result<Duration, Stop>,
+) where
+    E: StdError + 'static,
+{
+    let authority = req
+        .uri()
+        .authority()
+        .map(|authority| authority.to_string())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+
+    match decision {
+        Ok(delay) => {
+            let reason = match result {
+                Ok(response) => response.status().as_str().to_owned(),
+                Err(err) => format!("{:?}", ErrorKind::of(err)),
+            };
+            ::metrics::counter!(
+                RETRIES,
+                "authority" => authority.clone(),
+                "method" => method.clone(),
+                "reason" => reason,
+            )
+            .increment(1);
+            ::metrics::histogram!(SLEEP, "authority" => authority, "method" => method)
+                .record(delay.as_secs_f64());
+        }
+        Err(stop) => {
+            let reason = match stop {
+                Stop::Attempts => Some("attempts"),
+                Stop::Deadline => Some("deadline"),
+                Stop::Budget => Some("budget"),
+                Stop::Classified | Stop::NotIdempotent | Stop::NotReplayable => None,
+            };
+            if let Some(reason) = reason {
+                ::metrics::counter!(
+                    GIVE_UPS,
+                    "authority" => authority.clone(),
+                    "method" => method.clone(),
+                    "reason" => reason,
+                )
+                .increment(1);
+            }
+            ::metrics::histogram!(ATTEMPTS, "authority" => authority, "method" => method)
+                .record(attempt as f64);
+        }
+    }
+}
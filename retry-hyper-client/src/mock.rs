@@ -0,0 +1,128 @@
+//! Scripted service for the tests of the layers
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use tokio::time::{sleep, Instant};
+use tower::Service;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Service answering each request with the next scripted reply, `200 OK` once they run out
+///
+/// Clones share the same script and record the same requests.
+#[derive(Clone, Default)]
+pub struct Mock(Arc<Mutex<Script>>);
+
+#[derive(Default)]
+struct Script {
+    replies: VecDeque<(Duration, Result<Response<Body>, MockError>)>,
+    requests: Vec<Seen>,
+}
+
+/// Head of a request received by [`Mock`]
+#[derive(Debug)]
+pub struct Seen {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub attempt: Option<usize>,
+    pub at: Instant,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond with `status`
+    pub fn status(self, status: u16) -> Self {
+        self.status_after(Duration::ZERO, status)
+    }
+
+    /// Respond with `status` after `delay`
+    pub fn status_after(self, delay: Duration, status: u16) -> Self {
+        let response = Response::builder()
+            .status(StatusCode::from_u16(status).unwrap())
+            .body(Body::empty())
+            .unwrap();
+        self.reply_after(delay, Ok(response))
+    }
+
+    /// Fail with `err`
+    pub fn error(self, err: impl Into<BoxError>) -> Self {
+        self.reply_after(Duration::ZERO, Err(MockError(err.into())))
+    }
+
+    pub fn reply_after(self, delay: Duration, reply: Result<Response<Body>, MockError>) -> Self {
+        self.lock().replies.push_back((delay, reply));
+        self
+    }
+
+    /// Requests received so far, in order
+    pub fn requests(&self) -> Vec<Seen> {
+        self.lock().requests.drain(..).collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Script> {
+        self.0.lock().expect("mock lock poisoned")
+    }
+}
+
+impl<T> Service<Request<T>> for Mock {
+    type Response = Response<Body>;
+    type Error = MockError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, MockError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        let mut script = self.lock();
+        script.requests.push(Seen {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            attempt: req
+                .extensions()
+                .get::<crate::backoff::Attempt>()
+                .map(|attempt| attempt.0),
+            at: Instant::now(),
+        });
+        let (delay, reply) = script
+            .replies
+            .pop_front()
+            .unwrap_or_else(|| (Duration::ZERO, Ok(Response::new(Body::empty()))));
+        Box::pin(async move {
+            sleep(delay).await;
+            reply
+        })
+    }
+}
+
+/// Error returned by [`Mock`], with the scripted error as its source
+#[derive(Debug)]
+pub struct MockError(pub BoxError);
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("mock error")
+    }
+}
+
+impl StdError for MockError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.0)
+    }
+}
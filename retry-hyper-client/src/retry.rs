@@ -0,0 +1,700 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+
+use hyper::{Request, Response};
+use pin_project_lite::pin_project;
+use tower::{layer::util::Stack, retry::Policy, Layer, Service};
+
+use crate::{
+    backoff::Stop,
+    error_kind::ErrorKind,
+    history::{AttemptHistory, Outcome},
+    timeout::TimeoutLayer,
+};
+
+/// Retry requests according to a [`Policy`]
+///
+/// Unlike `tower::retry::RetryLayer`, which sends the caller's request as is on the first attempt,
+/// every attempt made by this layer is built with [`Policy::clone_request`]. The first clone is
+/// kept as a template for all attempts, so anything the policy adds to it (such as an
+/// `Idempotency-Key`) is sent on every attempt, including the first one.
+///
+/// The [`AttemptHistory`] of each request is added to the extensions of its final response, or to
+/// the [`RetryError`] if it failed. Responses that should have been retried, but ran out of
+/// attempts, time or budget, or couldn't be sent again, are returned as a [`RetryError`] too.
+///
+/// With the `tracing` feature, each request is wrapped in a `retry` span, with a child `attempt`
+/// span around each attempt.
+#[derive(Clone, Debug)]
+pub struct RetryLayer<P> {
+    policy: P,
+}
+
+impl<P> RetryLayer<P> {
+    pub fn new(policy: P) -> Self {
+        Self { policy }
+    }
+
+    /// Apply `timeout` to each attempt
+    #[allow(dead_code)]
+    pub fn with_timeout(self, timeout: TimeoutLayer) -> Stack<TimeoutLayer, Self> {
+        Stack::new(timeout, self)
+    }
+}
+
+impl<P, S> Layer<S> for RetryLayer<P>
+where
+    P: Clone,
+{
+    type Service = Retry<P, S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Retry {
+            policy: self.policy.clone(),
+            service,
+        }
+    }
+}
+
+/// [`Policy`] that can tell why it stopped retrying a request
+pub trait RetryPolicy<Req, Res, E>: Policy<Req, Res, E> {
+    /// Same as [`Policy::retry`], with the reason instead of `None`
+    fn retry_or_stop(&self, req: &Req, result: Result<&Res, &E>) -> Result<Self::Future, Stop>;
+}
+
+/// Marks the template request of [`Retry`], from which every attempt is cloned
+///
+/// The policy is asked whether to retry with the template, so this tells it that anything it
+/// added to the template was sent with the attempt that just failed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Template;
+
+/// Service created by [`RetryLayer`]
+#[derive(Clone, Debug)]
+pub struct Retry<P, S> {
+    policy: P,
+    service: S,
+}
+
+impl<P, S, T, B> Service<Request<T>> for Retry<P, S>
+where
+    P: RetryPolicy<Request<T>, Response<B>, S::Error> + Clone,
+    S: Service<Request<T>, Response = Response<B>> + Clone,
+    S::Error: StdError + 'static,
+{
+    type Response = Response<B>;
+    type Error = RetryError<S::Error, B>;
+    type Future = ResponseFuture<P, S, Request<T>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(|error| {
+            RetryError::NonRetryable(Box::new(Failures {
+                last: Failure::Error(error),
+                earlier: Vec::new(),
+                history: AttemptHistory::default(),
+            }))
+        })
+    }
+
+    fn call(&mut self, request: Request<T>) -> Self::Future {
+        let spans = Spans::new();
+        let _attempt = spans.enter_attempt();
+
+        let policy = self.policy.clone();
+        // If the request can't be cloned, send it once without retrying
+        let (template, request) = match policy.clone_request(&request) {
+            Some(mut template) => match policy.clone_request(&template) {
+                Some(request) => {
+                    template.extensions_mut().insert(Template);
+                    (Some(template), request)
+                }
+                None => (None, template),
+            },
+            None => (None, request),
+        };
+        let future = self.service.call(request);
+
+        ResponseFuture {
+            spans,
+            attempt: 1,
+            start: Instant::now(),
+            history: AttemptHistory::default(),
+            earlier: Vec::new(),
+            template,
+            policy,
+            service: self.service.clone(),
+            state: State::Called { future },
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`Retry`]
+    pub struct ResponseFuture<P, S, Req>
+    where
+        P: Policy<Req, S::Response, S::Error>,
+        S: Service<Req>,
+    {
+        spans: Spans,
+        // Number of the current attempt, starting at 1
+        attempt: usize,
+        // When the current attempt was sent
+        start: Instant,
+        history: AttemptHistory,
+        // Failures of the attempts before the current one
+        earlier: Vec<Failure<S::Error>>,
+        template: Option<Req>,
+        policy: P,
+        service: S,
+        #[pin]
+        state: State<S::Future, P::Future, Result<S::Response, S::Error>>,
+    }
+}
+
+pin_project! {
+    #[project = StateProj]
+    enum State<F, P, R> {
+        // Waiting for the response of an attempt
+        Called {
+            #[pin]
+            future: F,
+        },
+        // Waiting for the policy before the next attempt
+        //
+        // The previous result is kept around in case the next request can't be built.
+        Checking {
+            #[pin]
+            checking: P,
+            result: Option<R>,
+        },
+        // Waiting for the service to be ready for the next attempt
+        Retrying {
+            result: Option<R>,
+        },
+    }
+}
+
+impl<P, S, T, B> Future for ResponseFuture<P, S, Request<T>>
+where
+    P: RetryPolicy<Request<T>, Response<B>, S::Error>,
+    S: Service<Request<T>, Response = Response<B>>,
+    S::Error: StdError + 'static,
+{
+    type Output = Result<Response<B>, RetryError<S::Error, B>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+
+        loop {
+            let _request = this.spans.enter_request();
+            match this.state.as_mut().project() {
+                StateProj::Called { future } => {
+                    let _attempt = this.spans.enter_attempt();
+                    let result = ready!(future.poll(cx));
+                    this.history.push(*this.start, Outcome::of(result.as_ref()));
+                    let template = match this.template {
+                        Some(template) => template,
+                        None => {
+                            return Poll::Ready(finish(result, None, this.earlier, this.history))
+                        }
+                    };
+                    match this.policy.retry_or_stop(template, result.as_ref()) {
+                        Ok(checking) => this.state.set(State::Checking {
+                            checking,
+                            result: Some(result),
+                        }),
+                        Err(stop) => {
+                            let result = finish(result, Some(stop), this.earlier, this.history);
+                            return Poll::Ready(result);
+                        }
+                    }
+                }
+                StateProj::Checking { checking, result } => {
+                    *this.policy = ready!(checking.poll(cx));
+                    let result = result.take();
+                    this.state.set(State::Retrying { result });
+                }
+                StateProj::Retrying { result } => {
+                    if let Err(error) = ready!(this.service.poll_ready(cx)) {
+                        if let Some(result) = result.take() {
+                            this.earlier.push(Failure::without_body(result));
+                        }
+                        return Poll::Ready(finish(Err(error), None, this.earlier, this.history));
+                    }
+                    let template = this
+                        .template
+                        .as_ref()
+                        .expect("retrying requires a template request");
+                    *this.attempt += 1;
+                    this.spans.next_attempt(*this.attempt);
+                    let _attempt = this.spans.enter_attempt();
+                    let request = match this.policy.clone_request(template) {
+                        Some(request) => request,
+                        None => {
+                            // The policy wanted to retry, but the request can't be sent again
+                            let result = result.take().expect("polled after completion");
+                            let stop = Some(Stop::NotReplayable);
+                            return Poll::Ready(finish(result, stop, this.earlier, this.history));
+                        }
+                    };
+                    if let Some(result) = result.take() {
+                        this.earlier.push(Failure::without_body(result));
+                    }
+                    this.history.next_attempt();
+                    *this.start = Instant::now();
+                    this.state.set(State::Called {
+                        future: this.service.call(request),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Build the final result of a request, from the last result and why the policy stopped
+///
+/// `stop` is `None` when the policy wasn't asked, because the request couldn't be retried.
+fn finish<B, E>(
+    result: Result<Response<B>, E>,
+    stop: Option<Stop>,
+    earlier: &mut Vec<Failure<E>>,
+    history: &mut AttemptHistory,
+) -> Result<Response<B>, RetryError<E, B>>
+where
+    E: StdError + 'static,
+{
+    let history = mem::take(history);
+    let last = match result {
+        // Only responses that should have been retried are failures
+        Ok(response)
+            if matches!(
+                stop,
+                Some(
+                    Stop::NotIdempotent
+                        | Stop::NotReplayable
+                        | Stop::Attempts
+                        | Stop::Deadline
+                        | Stop::Budget
+                )
+            ) =>
+        {
+            Failure::Response(response)
+        }
+        Ok(mut response) => {
+            response.extensions_mut().insert(history);
+            return Ok(response);
+        }
+        Err(error) => Failure::Error(error),
+    };
+    let circuit_open =
+        matches!(&last, Failure::Error(err) if ErrorKind::of(err) == ErrorKind::CircuitOpen);
+    let failures = Box::new(Failures {
+        last,
+        earlier: mem::take(earlier),
+        history,
+    });
+
+    Err(match stop {
+        _ if circuit_open => RetryError::CircuitOpen(failures),
+        Some(Stop::Attempts) => RetryError::AttemptsExhausted(failures),
+        Some(Stop::Deadline) => RetryError::DeadlineExceeded(failures),
+        Some(Stop::Budget) => RetryError::BudgetExhausted(failures),
+        _ => RetryError::NonRetryable(failures),
+    })
+}
+
+/// Error returned by [`Retry`] when a request failed
+///
+/// The variant tells why the request was not retried further.
+#[derive(Debug)]
+pub enum RetryError<E, B = ()> {
+    /// All the attempts were used
+    AttemptsExhausted(Box<Failures<E, B>>),
+    /// The next attempt couldn't start before the deadline
+    DeadlineExceeded(Box<Failures<E, B>>),
+    /// The retry budget was empty
+    BudgetExhausted(Box<Failures<E, B>>),
+    /// The circuit breaker rejected the last attempt
+    CircuitOpen(Box<Failures<E, B>>),
+    /// The last error was not worth retrying, or the request couldn't be sent again
+    ///
+    /// In the latter case, the last failure may be a response that should have been retried.
+    NonRetryable(Box<Failures<E, B>>),
+}
+
+#[allow(dead_code)]
+impl<E, B> RetryError<E, B> {
+    pub fn failures(&self) -> &Failures<E, B> {
+        match self {
+            Self::AttemptsExhausted(failures)
+            | Self::DeadlineExceeded(failures)
+            | Self::BudgetExhausted(failures)
+            | Self::CircuitOpen(failures)
+            | Self::NonRetryable(failures) => failures,
+        }
+    }
+
+    pub fn into_failures(self) -> Failures<E, B> {
+        match self {
+            Self::AttemptsExhausted(failures)
+            | Self::DeadlineExceeded(failures)
+            | Self::BudgetExhausted(failures)
+            | Self::CircuitOpen(failures)
+            | Self::NonRetryable(failures) => *failures,
+        }
+    }
+
+    pub fn history(&self) -> &AttemptHistory {
+        &self.failures().history
+    }
+}
+
+impl<E, B> fmt::Display for RetryError<E, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AttemptsExhausted(_) => f.write_str("retry attempts exhausted")?,
+            Self::DeadlineExceeded(_) => f.write_str("retry deadline exceeded")?,
+            Self::BudgetExhausted(_) => f.write_str("retry budget exhausted")?,
+            Self::CircuitOpen(_) => f.write_str("request rejected by the circuit breaker")?,
+            Self::NonRetryable(_) => f.write_str("request failed")?,
+        }
+        let failures = self.failures();
+        write!(f, " after {} attempts", failures.history.len())?;
+        if let Failure::Response(response) = &failures.last {
+            write!(f, " (last status {})", response.status())?;
+        }
+        Ok(())
+    }
+}
+
+impl<E, B> StdError for RetryError<E, B>
+where
+    E: StdError + 'static,
+    B: fmt::Debug,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match &self.failures().last {
+            Failure::Error(err) => Some(err),
+            Failure::Response(_) => None,
+        }
+    }
+}
+
+/// Failures of all the attempts of a request
+#[derive(Debug)]
+pub struct Failures<E, B = ()> {
+    last: Failure<E, B>,
+    earlier: Vec<Failure<E>>,
+    history: AttemptHistory,
+}
+
+#[allow(dead_code)]
+impl<E, B> Failures<E, B> {
+    /// Failure of the last attempt
+    ///
+    /// A response keeps its body, so that it can still be read.
+    pub fn last(&self) -> &Failure<E, B> {
+        &self.last
+    }
+
+    pub fn into_last(self) -> Failure<E, B> {
+        self.last
+    }
+
+    /// Failures of the attempts before the last one, in order
+    ///
+    /// Only the head of the responses is kept.
+    pub fn earlier(&self) -> &[Failure<E>] {
+        &self.earlier
+    }
+}
+
+/// How an attempt failed
+#[derive(Debug)]
+pub enum Failure<E, B = ()> {
+    /// The server responded with a status worth retrying
+    Response(Response<B>),
+    /// The attempt failed with an error
+    Error(E),
+}
+
+impl<E> Failure<E> {
+    /// Failure of an attempt that is being retried, without the body of the response
+    fn without_body<B>(result: Result<Response<B>, E>) -> Self {
+        match result {
+            Ok(response) => Self::Response(response.map(|_| ())),
+            Err(error) => Self::Error(error),
+        }
+    }
+}
+
+/// Spans of a request and of its current attempt, only recorded with the `tracing` feature
+#[derive(Debug)]
+struct Spans {
+    #[cfg(feature = "tracing")]
+    request: tracing::Span,
+    #[cfg(feature = "tracing")]
+    attempt: tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+type Entered = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+struct Entered;
+
+impl Spans {
+    fn new() -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            let request = tracing::debug_span!("retry");
+            let attempt = tracing::debug_span!(parent: &request, "attempt", attempt = 1);
+            Self { request, attempt }
+        }
+        #[cfg(not(feature = "tracing"))]
+        Self {}
+    }
+
+    /// Replace the attempt span with the one of the given attempt
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn next_attempt(&mut self, attempt: usize) {
+        #[cfg(feature = "tracing")]
+        {
+            self.attempt = tracing::debug_span!(parent: &self.request, "attempt", attempt);
+        }
+    }
+
+    fn enter_request(&self) -> Entered {
+        #[cfg(feature = "tracing")]
+        return self.request.clone().entered();
+        #[cfg(not(feature = "tracing"))]
+        Entered
+    }
+
+    fn enter_attempt(&self) -> Entered {
+        #[cfg(feature = "tracing")]
+        return self.attempt.clone().entered();
+        #[cfg(not(feature = "tracing"))]
+        Entered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use hyper::{body::to_bytes, Body, Method};
+    use tower::{retry::budget::Budget, service_fn, ServiceExt};
+
+    use super::*;
+    use crate::{
+        backoff::Backoff,
+        body::ReplayBody,
+        circuit::CircuitBreakerLayer,
+        classify::DefaultClassifier,
+        mock::{Mock, MockError},
+        sleep::ManualClock,
+    };
+
+    type TestError = RetryError<MockError, Body>;
+
+    fn backoff() -> Backoff<DefaultClassifier, ManualClock> {
+        Backoff::new().with_sleeper(ManualClock::new())
+    }
+
+    fn request(method: Method) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri("http://localhost/")
+            .body(())
+            .unwrap()
+    }
+
+    async fn send<S>(
+        policy: Backoff<DefaultClassifier, ManualClock>,
+        service: S,
+        method: Method,
+    ) -> Result<Response<Body>, RetryError<S::Error, Body>>
+    where
+        S: Service<Request<()>, Response = Response<Body>> + Clone,
+        S::Error: StdError + 'static,
+    {
+        RetryLayer::new(policy)
+            .layer(service)
+            .oneshot(request(method))
+            .await
+    }
+
+    fn status<E, B>(failure: &Failure<E, B>) -> Option<u16> {
+        match failure {
+            Failure::Response(response) => Some(response.status().as_u16()),
+            Failure::Error(_) => None,
+        }
+    }
+
+    fn statuses(history: &AttemptHistory) -> Vec<String> {
+        history
+            .attempts()
+            .iter()
+            .map(|attempt| attempt.outcome().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn attempts_exhausted() {
+        let mock = Mock::new().status(500).status(502).status(503);
+        let err: TestError = send(backoff().with_attempts(2), mock, Method::GET)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RetryError::AttemptsExhausted(_)), "{err:?}");
+        assert_eq!(
+            err.to_string(),
+            "retry attempts exhausted after 3 attempts (last status 503 Service Unavailable)"
+        );
+        assert!(err.source().is_none());
+
+        let history = err.history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(
+            statuses(history),
+            [
+                "500 Internal Server Error",
+                "502 Bad Gateway",
+                "503 Service Unavailable"
+            ]
+        );
+        assert!(history.attempts()[0].delay().is_some());
+        assert_eq!(history.attempts()[2].delay(), None);
+
+        let failures = err.into_failures();
+        let earlier: Vec<_> = failures.earlier().iter().map(status).collect();
+        assert_eq!(earlier, [Some(500), Some(502)]);
+        assert_eq!(status(failures.last()), Some(503));
+    }
+
+    #[tokio::test]
+    async fn deadline_exceeded() {
+        let policy = backoff()
+            .with_delay(Duration::from_millis(100))
+            .with_max_elapsed(Duration::from_millis(150));
+        let mock = Mock::new().status(503).status(503).status(503);
+        let err: TestError = send(policy, mock.clone(), Method::GET).await.unwrap_err();
+        assert!(matches!(err, RetryError::DeadlineExceeded(_)), "{err:?}");
+        // The last attempt is made right at the deadline
+        assert_eq!(mock.requests().len(), 3);
+        assert_eq!(err.history().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn budget_exhausted() {
+        let budget = Arc::new(Budget::new(Duration::from_secs(10), 0, 0.0));
+        let mock = Mock::new().status(503);
+        let err: TestError = send(backoff().with_budget(budget), mock, Method::GET)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RetryError::BudgetExhausted(_)), "{err:?}");
+        assert_eq!(err.history().len(), 1);
+        assert!(err.failures().earlier().is_empty());
+    }
+
+    #[tokio::test]
+    async fn circuit_open() {
+        let breaker = CircuitBreakerLayer::new().with_consecutive_failures(1);
+        let mock = Mock::new().status(503).status(503);
+        let err = send(
+            backoff().with_attempts(3),
+            breaker.layer(mock.clone()),
+            Method::GET,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, RetryError::CircuitOpen(_)), "{err:?}");
+        // The circuit opened after the first attempt, and rejected the second one
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(err.history().len(), 2);
+        let source = err.source().unwrap();
+        assert_eq!(ErrorKind::of(source), ErrorKind::CircuitOpen);
+
+        let failures = err.into_failures();
+        assert_eq!(status(&failures.earlier()[0]), Some(503));
+        assert!(matches!(failures.last(), Failure::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn non_retryable_error() {
+        let mock = Mock::new().status(503).error("fatal");
+        let err: TestError = send(backoff(), mock, Method::GET).await.unwrap_err();
+        assert!(matches!(err, RetryError::NonRetryable(_)), "{err:?}");
+        assert_eq!(err.to_string(), "request failed after 2 attempts");
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "mock error");
+        assert_eq!(source.source().unwrap().to_string(), "fatal");
+        assert_eq!(
+            statuses(err.history()),
+            ["503 Service Unavailable", "Other: mock error"]
+        );
+    }
+
+    #[tokio::test]
+    async fn not_idempotent_response() {
+        let mock = Mock::new().status(503);
+        let err: TestError = send(backoff(), mock.clone(), Method::POST)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RetryError::NonRetryable(_)), "{err:?}");
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(status(err.failures().last()), Some(503));
+        assert!(err.source().is_none());
+    }
+
+    #[tokio::test]
+    async fn not_replayable_response() {
+        let mock = Mock::new().status(503);
+        // Read the body before answering, which makes it go over its limit
+        let service = service_fn(|req: Request<ReplayBody>| {
+            let mut mock = mock.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                to_bytes(body).await.unwrap();
+                mock.call(Request::from_parts(parts, ())).await
+            }
+        });
+        let req = Request::put("http://localhost/")
+            .body(ReplayBody::new(Body::from("too large"), 4))
+            .unwrap();
+
+        let err = RetryLayer::new(backoff())
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RetryError::NonRetryable(_)), "{err:?}");
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(status(err.failures().last()), Some(503));
+    }
+
+    #[tokio::test]
+    async fn success_history() {
+        let mock = Mock::new().status(503).error("reset");
+        let policy = backoff()
+            .with_classifier(DefaultClassifier::new().with_retryable_error(ErrorKind::Other));
+        let response = send(policy, mock, Method::GET).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let history = response.extensions().get::<AttemptHistory>().unwrap();
+        assert_eq!(
+            statuses(history),
+            ["503 Service Unavailable", "Other: mock error", "200 OK"]
+        );
+        assert!(history.attempts()[..2]
+            .iter()
+            .all(|attempt| attempt.delay().is_some()));
+        assert_eq!(history.attempts()[2].delay(), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{header::RETRY_AFTER, HeaderMap};
+
+/// Headers that can tell us when the server is willing to accept a new request
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Values above this are treated as Unix timestamps rather than delta-seconds
+///
+/// `X-RateLimit-Reset` is not standardised: some servers send the number of seconds until the
+/// window resets, others the Unix time at which it resets.
+const UNIX_TIMESTAMP_THRESHOLD: f64 = 1_000_000_000.0;
+
+/// Returns how long the server asked us to wait before the next request
+///
+/// This looks at `Retry-After` (delta-seconds or HTTP-date), `RateLimit-Reset` and
+/// `X-RateLimit-Reset`, and returns the longest wait found, if any.
+pub fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, now));
+    let ratelimit_reset = headers
+        .get(RATELIMIT_RESET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_seconds);
+    let x_ratelimit_reset = headers
+        .get(X_RATELIMIT_RESET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_reset(value, now));
+
+    [retry_after, ratelimit_reset, x_ratelimit_reset]
+        .into_iter()
+        .flatten()
+        .max()
+}
+
+/// Parse a `Retry-After` value, either delta-seconds or an HTTP-date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Some(delay) = parse_seconds(value) {
+        return Some(delay);
+    }
+
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    // A date in the past means we can retry right away
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Parse an `X-RateLimit-Reset` value, either delta-seconds or a Unix timestamp
+fn parse_reset(value: &str, now: SystemTime) -> Option<Duration> {
+    let seconds = parse_f64(value)?;
+    if seconds < UNIX_TIMESTAMP_THRESHOLD {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+
+    let reset = UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(seconds).ok()?)?;
+    Some(reset.duration_since(now).unwrap_or_default())
+}
+
+/// Parse a non-negative number of seconds
+fn parse_seconds(value: &str) -> Option<Duration> {
+    parse_f64(value).and_then(|value| Duration::try_from_secs_f64(value).ok())
+}
+
+fn parse_f64(value: &str) -> Option<f64> {
+    let value: f64 = value.trim().parse().ok()?;
+    if value.is_finite() && value >= 0.0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    #[test]
+    fn retry_after_delta_seconds() {
+        assert_eq!(
+            parse_retry_after("120", now()),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(" 1.5 ", now()),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let date = httpdate::fmt_http_date(now() + Duration::from_secs(30));
+        assert_eq!(
+            parse_retry_after(&date, now()),
+            Some(Duration::from_secs(30))
+        );
+        let past = httpdate::fmt_http_date(now() - Duration::from_secs(30));
+        assert_eq!(parse_retry_after(&past, now()), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_invalid() {
+        assert_eq!(parse_retry_after("-5", now()), None);
+        assert_eq!(parse_retry_after("soon", now()), None);
+        assert_eq!(parse_retry_after("NaN", now()), None);
+        assert_eq!(parse_retry_after("", now()), None);
+    }
+
+    #[test]
+    fn reset_delta_seconds() {
+        assert_eq!(parse_reset("60", now()), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn reset_unix_timestamp() {
+        assert_eq!(
+            parse_reset("1700000045", now()),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(parse_reset("1699999000", now()), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reset_invalid() {
+        assert_eq!(parse_reset("-1", now()), None);
+        assert_eq!(parse_reset("later", now()), None);
+        assert_eq!(parse_reset("inf", now()), None);
+    }
+
+    #[test]
+    fn longest_wait_wins() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("10"));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from_static("20"));
+        headers.insert(X_RATELIMIT_RESET, HeaderValue::from_static("1700000005"));
+        assert_eq!(retry_after(&headers, now()), Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now()), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("garbage"));
+        headers.insert(X_RATELIMIT_RESET, HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers, now()), Some(Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,40 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+/// Random number generator used for the jitter of a [`Backoff`](crate::backoff::Backoff)
+///
+/// Cloning it forks a new generator, seeded from the next value of this one. Clones therefore
+/// draw independent streams, which are still fully determined by the original generator as long
+/// as the clones are made in the same order.
+pub struct JitterRng(Arc<Mutex<Box<dyn RngCore + Send>>>);
+
+impl JitterRng {
+    pub fn new<R: RngCore + Send + 'static>(rng: R) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(rng))))
+    }
+
+    pub fn from_seed(seed: u64) -> Self {
+        Self::new(StdRng::seed_from_u64(seed))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Box<dyn RngCore + Send>> {
+        self.0.lock().expect("jitter rng lock poisoned")
+    }
+}
+
+impl Clone for JitterRng {
+    fn clone(&self) -> Self {
+        let seed = self.lock().next_u64();
+        Self::from_seed(seed)
+    }
+}
+
+impl fmt::Debug for JitterRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("JitterRng").finish_non_exhaustive()
+    }
+}
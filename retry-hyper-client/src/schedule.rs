@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Delays between attempts, before jitter is applied
+///
+/// Implementations don't need to cap the delays, [`Backoff`](crate::backoff::Backoff) applies its
+/// maximum delay on top of the schedule.
+pub trait DelaySchedule: Send + Sync {
+    /// Delay before the given retry, starting at 0 for the delay between the first and second
+    /// attempts
+    fn delay(&self, retry: usize) -> Duration;
+}
+
+/// Same delay between all attempts
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct Constant(pub Duration);
+
+impl DelaySchedule for Constant {
+    fn delay(&self, _retry: usize) -> Duration {
+        self.0
+    }
+}
+
+/// Delay growing by `increment` after each attempt
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct Linear {
+    pub initial: Duration,
+    pub increment: Duration,
+}
+
+impl DelaySchedule for Linear {
+    fn delay(&self, retry: usize) -> Duration {
+        let retry = retry.min(u32::MAX as usize) as u32;
+        self.increment
+            .checked_mul(retry)
+            .and_then(|increment| self.initial.checked_add(increment))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Delay multiplied by `multiplier` after each attempt
+#[derive(Clone, Copy, Debug)]
+pub struct Exponential {
+    pub initial: Duration,
+    pub multiplier: f64,
+}
+
+impl DelaySchedule for Exponential {
+    fn delay(&self, retry: usize) -> Duration {
+        let retry = retry.min(i32::MAX as usize) as i32;
+        Duration::try_from_secs_f64(self.initial.as_secs_f64() * self.multiplier.powi(retry))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Delays following the Fibonacci sequence, in multiples of `unit`
+///
+/// With a 100ms unit, the delays are 100ms, 100ms, 200ms, 300ms, 500ms, 800ms, and so on.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct Fibonacci(pub Duration);
+
+impl DelaySchedule for Fibonacci {
+    fn delay(&self, retry: usize) -> Duration {
+        let (mut current, mut next) = (1u32, 1u32);
+        for _ in 0..retry {
+            (current, next) = (next, current.saturating_add(next));
+            if current == u32::MAX {
+                break;
+            }
+        }
+        self.0.checked_mul(current).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Explicit list of delays
+///
+/// The last delay is repeated if there are more retries than delays, so the number of attempts
+/// should usually be set to one more than the length of the list.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct List(pub Vec<Duration>);
+
+impl DelaySchedule for List {
+    fn delay(&self, retry: usize) -> Duration {
+        self.0
+            .get(retry)
+            .or_else(|| self.0.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl From<Vec<Duration>> for List {
+    fn from(delays: Vec<Duration>) -> Self {
+        Self(delays)
+    }
+}
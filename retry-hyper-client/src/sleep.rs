@@ -0,0 +1,164 @@
+#[cfg(any(feature = "async-std", feature = "smol"))]
+use std::pin::Pin;
+#[cfg(feature = "smol")]
+use std::task::{Context, Poll};
+use std::{
+    future::{self, Future, Ready},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Timer used by [`Backoff`](crate::backoff::Backoff) to wait between attempts
+///
+/// The clock of a sleeper has to move along with its sleeps, as it is used to measure how much
+/// time the retries took.
+pub trait Sleeper: Clock {
+    type Sleep: Future<Output = ()> + Send;
+
+    fn sleep(&self, delay: Duration) -> Self::Sleep;
+}
+
+/// Tokio's timer
+///
+/// This follows `tokio::time::pause` and `tokio::time::advance`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioSleeper;
+
+impl Clock for TokioSleeper {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+impl Sleeper for TokioSleeper {
+    type Sleep = tokio::time::Sleep;
+
+    fn sleep(&self, delay: Duration) -> Self::Sleep {
+        tokio::time::sleep(delay)
+    }
+}
+
+/// async-std's timer
+#[cfg(feature = "async-std")]
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdSleeper;
+
+#[cfg(feature = "async-std")]
+impl Clock for AsyncStdSleeper {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(feature = "async-std")]
+impl Sleeper for AsyncStdSleeper {
+    // async-std doesn't name the future of its timer
+    type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    fn sleep(&self, delay: Duration) -> Self::Sleep {
+        Box::pin(async_std::task::sleep(delay))
+    }
+}
+
+/// smol's timer
+#[cfg(feature = "smol")]
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolSleeper;
+
+#[cfg(feature = "smol")]
+impl Clock for SmolSleeper {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(feature = "smol")]
+impl Sleeper for SmolSleeper {
+    type Sleep = SmolSleep;
+
+    fn sleep(&self, delay: Duration) -> Self::Sleep {
+        SmolSleep(smol::Timer::after(delay))
+    }
+}
+
+/// Future returned by [`SmolSleeper`]
+#[cfg(feature = "smol")]
+#[derive(Debug)]
+pub struct SmolSleep(smol::Timer);
+
+#[cfg(feature = "smol")]
+impl Future for SmolSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+/// Clock that only moves when told to, for tests
+///
+/// Sleeps complete immediately, after advancing the clock by their delay, and are recorded so
+/// that they can be checked afterwards. Clones share the same time and the same record.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<Manual>>);
+
+#[derive(Debug)]
+struct Manual {
+    now: Instant,
+    sleeps: Vec<Duration>,
+}
+
+#[allow(dead_code)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Manual {
+            now: Instant::now(),
+            sleeps: Vec::new(),
+        })))
+    }
+
+    /// Move the clock forward by `duration`, without recording a sleep
+    pub fn advance(&self, duration: Duration) {
+        self.lock().now += duration;
+    }
+
+    /// Delays of the sleeps requested so far, in order
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Manual> {
+        self.0.lock().expect("manual clock lock poisoned")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+}
+
+impl Sleeper for ManualClock {
+    type Sleep = Ready<()>;
+
+    fn sleep(&self, delay: Duration) -> Self::Sleep {
+        let mut manual = self.lock();
+        manual.now += delay;
+        manual.sleeps.push(delay);
+        future::ready(())
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use hyper::{
+    body::{HttpBody, SizeHint},
+    HeaderMap, Request, Response,
+};
+use pin_project_lite::pin_project;
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
+use tower::{Layer, Service};
+
+use crate::backoff::Attempt;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Apply a timeout to each attempt
+///
+/// This is meant to sit between the [`RetryLayer`](crate::retry::RetryLayer) and the client, so
+/// that an attempt that takes too long fails with a [`TimeoutError::Elapsed`], which is retried by
+/// the default classifier.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutLayer {
+    /// Maximum time to wait for the response headers
+    headers: Option<Duration>,
+    /// Maximum time to wait for the full response, including the body
+    response: Option<Duration>,
+    /// Multiplier applied to the timeouts for each attempt after the first one
+    multiplier: f64,
+}
+
+impl TimeoutLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn with_headers(self, headers: Duration) -> Self {
+        Self {
+            headers: Some(headers),
+            ..self
+        }
+    }
+
+    /// Timeout for the full response
+    ///
+    /// Reading the body past this deadline fails with an error. That error is only retried if the
+    /// body is read before the response is returned to the retry layer.
+    #[allow(dead_code)]
+    pub fn with_response(self, response: Duration) -> Self {
+        Self {
+            response: Some(response),
+            ..self
+        }
+    }
+
+    /// Multiply the timeouts by `multiplier` for each attempt after the first one
+    ///
+    /// Timeouts never shrink: `multiplier` is at least 1.0.
+    #[allow(dead_code)]
+    pub fn with_multiplier(self, multiplier: f64) -> Self {
+        let multiplier = if multiplier.is_nan() {
+            1.0
+        } else {
+            multiplier.max(1.0)
+        };
+        Self { multiplier, ..self }
+    }
+
+    /// Timeout for the given attempt, starting at 1
+    fn scale(&self, timeout: Option<Duration>, attempt: usize) -> Option<Duration> {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        timeout.map(|timeout| {
+            Duration::try_from_secs_f64(timeout.as_secs_f64() * self.multiplier.powi(exponent))
+                .unwrap_or(Duration::MAX)
+        })
+    }
+}
+
+impl Default for TimeoutLayer {
+    fn default() -> Self {
+        Self {
+            headers: None,
+            response: None,
+            multiplier: 1.0,
+        }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Timeout {
+            layer: *self,
+            service,
+        }
+    }
+}
+
+/// Service created by [`TimeoutLayer`]
+#[derive(Clone, Debug)]
+pub struct Timeout<S> {
+    layer: TimeoutLayer,
+    service: S,
+}
+
+impl<S, T, B> Service<Request<T>> for Timeout<S>
+where
+    S: Service<Request<T>, Response = Response<B>>,
+{
+    type Response = Response<TimeoutBody<B>>;
+    type Error = TimeoutError<S::Error>;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(TimeoutError::Inner)
+    }
+
+    fn call(&mut self, req: Request<T>) -> Self::Future {
+        // Set by `Backoff::clone_request`
+        let attempt = req
+            .extensions()
+            .get::<Attempt>()
+            .map_or(1, |attempt| attempt.0);
+        let headers = self.layer.scale(self.layer.headers, attempt);
+        let response = self.layer.scale(self.layer.response, attempt);
+
+        // The headers are part of the response, so they can't take longer than it
+        let headers = match (headers, response) {
+            (Some(headers), Some(response)) => Some(headers.min(response)),
+            (headers, response) => headers.or(response),
+        };
+
+        let start = Instant::now();
+        ResponseFuture {
+            future: self.service.call(req),
+            sleep: headers.map(|headers| Box::pin(sleep(headers))),
+            headers,
+            // A deadline too far away to represent is no deadline at all
+            deadline: response.and_then(|response| Some((start.checked_add(response)?, response))),
+        }
+    }
+}
+
+pin_project! {
+    /// Future returned by [`Timeout`]
+    pub struct ResponseFuture<F> {
+        #[pin]
+        future: F,
+        sleep: Option<Pin<Box<Sleep>>>,
+        headers: Option<Duration>,
+        deadline: Option<(Instant, Duration)>,
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<TimeoutBody<B>>, TimeoutError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(result) = this.future.poll(cx) {
+            let deadline = *this.deadline;
+            return Poll::Ready(
+                result
+                    .map(|response| response.map(|body| TimeoutBody::new(body, deadline)))
+                    .map_err(TimeoutError::Inner),
+            );
+        }
+
+        if let (Some(sleep), Some(headers)) = (this.sleep.as_mut(), *this.headers) {
+            ready!(sleep.as_mut().poll(cx));
+            return Poll::Ready(Err(TimeoutError::Elapsed(Elapsed {
+                phase: Phase::Headers,
+                after: headers,
+            })));
+        }
+        Poll::Pending
+    }
+}
+
+pin_project! {
+    /// Response body that fails once the full response timeout has elapsed
+    #[derive(Debug)]
+    pub struct TimeoutBody<B> {
+        #[pin]
+        body: B,
+        sleep: Option<Pin<Box<Sleep>>>,
+        timeout: Duration,
+    }
+}
+
+impl<B> TimeoutBody<B> {
+    fn new(body: B, deadline: Option<(Instant, Duration)>) -> Self {
+        let (sleep, timeout) = match deadline {
+            Some((deadline, timeout)) => (Some(Box::pin(sleep_until(deadline))), timeout),
+            None => (None, Duration::ZERO),
+        };
+        Self {
+            body,
+            sleep,
+            timeout,
+        }
+    }
+}
+
+impl<B> TimeoutBody<B> {
+    fn poll_elapsed(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Option<BoxError> {
+        let this = self.project();
+        let sleep = this.sleep.as_mut()?;
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Some(
+                Elapsed {
+                    phase: Phase::Response,
+                    after: *this.timeout,
+                }
+                .into(),
+            ),
+            Poll::Pending => None,
+        }
+    }
+}
+
+impl<B> HttpBody for TimeoutBody<B>
+where
+    B: HttpBody,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if let Poll::Ready(data) = self.as_mut().project().body.poll_data(cx) {
+            return Poll::Ready(data.map(|data| data.map_err(Into::into)));
+        }
+        match self.poll_elapsed(cx) {
+            Some(err) => Poll::Ready(Some(Err(err))),
+            None => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        if let Poll::Ready(trailers) = self.as_mut().project().body.poll_trailers(cx) {
+            return Poll::Ready(trailers.map_err(Into::into));
+        }
+        match self.poll_elapsed(cx) {
+            Some(err) => Poll::Ready(Err(err)),
+            None => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Error returned by [`Timeout`]
+#[derive(Debug)]
+pub enum TimeoutError<E> {
+    /// The attempt took too long
+    Elapsed(Elapsed),
+    /// The inner service failed
+    Inner(E),
+}
+
+impl<E> fmt::Display for TimeoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elapsed(_) => f.write_str("attempt timed out"),
+            Self::Inner(_) => f.write_str("attempt failed"),
+        }
+    }
+}
+
+impl<E> StdError for TimeoutError<E>
+where
+    E: StdError + 'static,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Elapsed(err) => Some(err),
+            Self::Inner(err) => Some(err),
+        }
+    }
+}
+
+/// An attempt timed out
+#[derive(Clone, Copy, Debug)]
+pub struct Elapsed {
+    phase: Phase,
+    after: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Headers,
+    Response,
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self.phase {
+            Phase::Headers => "response headers",
+            Phase::Response => "full response",
+        };
+        write!(f, "no {} after {}ms", phase, self.after.as_millis())
+    }
+}
+
+impl StdError for Elapsed {}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{body::to_bytes, Body};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::mock::Mock;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn request(attempt: usize) -> Request<()> {
+        let mut req = Request::new(());
+        req.extensions_mut().insert(Attempt(attempt));
+        req
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn headers_timeout() {
+        let layer = TimeoutLayer::new().with_headers(ms(100));
+        let mock = Mock::new()
+            .status_after(ms(150), 200)
+            .status_after(ms(50), 200);
+        let start = Instant::now();
+
+        let err = layer
+            .layer(mock.clone())
+            .oneshot(request(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TimeoutError::Elapsed(_)), "{err:?}");
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "no response headers after 100ms"
+        );
+        assert_eq!(start.elapsed(), ms(100));
+
+        let response = layer.layer(mock).oneshot(request(1)).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn response_deadline_covers_body() {
+        let layer = TimeoutLayer::new().with_response(ms(100));
+        let (mut sender, body) = Body::channel();
+        let mock = Mock::new().reply_after(ms(40), Ok(Response::new(body)));
+        let start = Instant::now();
+
+        let response = layer.layer(mock).oneshot(request(1)).await.unwrap();
+        assert_eq!(start.elapsed(), ms(40));
+        sender.send_data("partial".into()).await.unwrap();
+
+        // The sender is still open, so the body only ends with the deadline
+        let err = to_bytes(response.into_body()).await.unwrap_err();
+        assert_eq!(err.to_string(), "no full response after 100ms");
+        assert_eq!(start.elapsed(), ms(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn response_deadline_bounds_headers() {
+        let layer = TimeoutLayer::new()
+            .with_headers(ms(500))
+            .with_response(ms(100));
+        let mock = Mock::new().status_after(ms(200), 200);
+        let start = Instant::now();
+
+        let err = layer.layer(mock).oneshot(request(1)).await.unwrap_err();
+        assert!(matches!(err, TimeoutError::Elapsed(_)), "{err:?}");
+        assert_eq!(start.elapsed(), ms(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scales_with_attempt() {
+        let layer = TimeoutLayer::new()
+            .with_headers(ms(100))
+            .with_multiplier(2.0);
+        let mock = Mock::new()
+            .status_after(ms(300), 200)
+            .status_after(ms(500), 200);
+        let start = Instant::now();
+
+        // 400ms for the third attempt
+        let response = layer.layer(mock.clone()).oneshot(request(3)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(start.elapsed(), ms(300));
+
+        let err = layer.layer(mock).oneshot(request(3)).await.unwrap_err();
+        assert!(matches!(err, TimeoutError::Elapsed(_)), "{err:?}");
+        assert_eq!(start.elapsed(), ms(700));
+    }
+
+    #[test]
+    fn scale() {
+        let layer = TimeoutLayer::new().with_multiplier(2.0);
+        assert_eq!(layer.scale(None, 2), None);
+        assert_eq!(layer.scale(Some(ms(100)), 0), Some(ms(100)));
+        assert_eq!(layer.scale(Some(ms(100)), 1), Some(ms(100)));
+        assert_eq!(layer.scale(Some(ms(100)), 3), Some(ms(400)));
+        assert_eq!(layer.scale(Some(ms(100)), usize::MAX), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn multiplier_is_at_least_one() {
+        for multiplier in [0.0, -2.0, 0.5, f64::NAN] {
+            let layer = TimeoutLayer::new().with_multiplier(multiplier);
+            assert_eq!(layer.multiplier, 1.0, "{multiplier}");
+            assert_eq!(layer.scale(Some(ms(100)), 5), Some(ms(100)));
+        }
+    }
+}